@@ -1,8 +1,100 @@
+use std::{env, fs, path::PathBuf};
+
+/// Every this many symbols the name is stored whole, so lookups don't have
+/// to decode the table from the start
+const RESTART_INTERVAL: usize = 16;
+
 fn main() {
-    if std::env::var("CARGO_CFG_TARGET_OS").unwrap() == "none" {
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() == "none" {
         // Tell cargo to pass the linker script to the linker..
         println!("cargo::rustc-link-arg-bins=-Tlinker.ld");
         // ..and to re-run if it changes.
         println!("cargo::rerun-if-changed=linker.ld");
     }
+
+    // the makefile links twice, the second time with the symbols of the first
+    println!("cargo::rerun-if-env-changed=KERNEL_SYMBOLS");
+    let table = match env::var("KERNEL_SYMBOLS") {
+        Ok(path) => {
+            println!("cargo::rerun-if-changed={path}");
+            let nm_output = fs::read_to_string(&path).expect("failed to read KERNEL_SYMBOLS");
+            encode(parse(&nm_output))
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("ksyms.bin"), table).unwrap();
+}
+
+/// Parses the output of `nm --defined-only --demangle`, keeping code symbols
+fn parse(nm_output: &str) -> Vec<(u64, String)> {
+    let mut symbols: Vec<_> = nm_output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ' ');
+            let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+            let kind = parts.next()?;
+            let name = parts.next()?;
+            matches!(kind, "T" | "t" | "W" | "w").then(|| (addr, strip_hash(name).to_owned()))
+        })
+        .collect();
+
+    symbols.sort();
+    // aliases share an address, keep one of them
+    symbols.dedup_by_key(|(addr, _)| *addr);
+    symbols
+}
+
+/// Legacy rust symbols end in a `::h` followed by a 16 digit hash
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            path
+        }
+        _ => name,
+    }
+}
+
+/// Layout, all little endian:
+/// - `count: u32`, `base: u64`
+/// - `count` entries of `offset: u32` (address - base) and `name: u32` (into names)
+/// - names: `shared: u16` bytes shared with the previous name, `len: u16`, then
+///   `len` bytes of suffix. `shared` is 0 every [RESTART_INTERVAL] names.
+fn encode(symbols: Vec<(u64, String)>) -> Vec<u8> {
+    let base = symbols.first().map_or(0, |(addr, _)| *addr);
+
+    let mut entries = Vec::new();
+    let mut names = Vec::new();
+    let mut previous = "";
+
+    for (i, (addr, name)) in symbols.iter().enumerate() {
+        let shared = if i % RESTART_INTERVAL == 0 {
+            0
+        } else {
+            previous
+                .bytes()
+                .zip(name.bytes())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        let suffix = &name.as_bytes()[shared..];
+        let suffix_len = u16::try_from(suffix.len()).expect("symbol name is too long");
+
+        let offset = u32::try_from(addr - base).expect("kernel text is too big");
+        entries.extend(offset.to_le_bytes());
+        entries.extend((names.len() as u32).to_le_bytes());
+
+        names.extend((shared as u16).to_le_bytes());
+        names.extend(suffix_len.to_le_bytes());
+        names.extend(suffix);
+        previous = name;
+    }
+
+    let mut table = Vec::new();
+    table.extend((symbols.len() as u32).to_le_bytes());
+    table.extend(base.to_le_bytes());
+    table.extend(entries);
+    table.extend(names);
+    table
 }
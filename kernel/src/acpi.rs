@@ -0,0 +1,141 @@
+//! ACPI table discovery
+//!
+//! Tables are found through the rsdp the bootloader hands us, and mapped
+//! once since they may live outside the memory covered by the hhdm.
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+
+use core::{mem::offset_of, slice};
+
+use alloc::vec::Vec;
+use spin::Once;
+
+use crate::{
+    boot,
+    mem::{mmio, MappingKind, PhysPtr},
+    println,
+};
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // only present from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// Header shared by every system description table
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Returns the table following the header
+    pub fn data(&self) -> &[u8] {
+        let len = self.length as usize - size_of::<Self>();
+        unsafe { slice::from_raw_parts(core::ptr::from_ref(self).add(1).cast(), len) }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(core::ptr::from_ref(self).cast(), self.length as usize) }
+    }
+}
+
+static TABLES: Once<Vec<&'static SdtHeader>> = Once::new();
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn map<T>(phys: PhysPtr<T>, size: usize) -> *const T {
+    mmio::map_physical(phys, size, MappingKind::ReadOnly).expect("critical mapping failed")
+}
+
+/// Maps a whole table, returning `None` if its checksum is wrong
+fn map_table(phys: PhysPtr<SdtHeader>) -> Option<&'static SdtHeader> {
+    let header = unsafe { map(phys, size_of::<SdtHeader>()).read_unaligned() };
+    let table = unsafe { &*map(phys, header.length as usize) };
+
+    if !checksum(table.bytes()) {
+        println!(
+            "acpi: ignoring table {} with bad checksum",
+            core::str::from_utf8(&header.signature).unwrap_or("????")
+        );
+        return None;
+    }
+    Some(table)
+}
+
+/// Finds every table listed by the firmware, must be called before [find_table]
+pub fn init() {
+    crate::assert_once!();
+
+    TABLES.call_once(|| {
+        let Some(rsdp_phys) = boot::rsdp() else {
+            println!("acpi: no rsdp");
+            return Vec::new();
+        };
+
+        let rsdp_ptr = map(rsdp_phys.cast::<Rsdp>(), size_of::<Rsdp>());
+        let rsdp = unsafe { rsdp_ptr.read_unaligned() };
+        assert_eq!(&rsdp.signature, b"RSD PTR ", "acpi: bad rsdp signature");
+        // the checksum only covers the revision 0 fields
+        let rsdp_bytes =
+            unsafe { slice::from_raw_parts(rsdp_ptr.cast::<u8>(), offset_of!(Rsdp, length)) };
+        assert!(checksum(rsdp_bytes), "acpi: bad rsdp checksum");
+
+        // prefer the xsdt, its entries are 64 bit
+        let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+            (rsdp.xsdt_address as usize, 8)
+        } else {
+            (rsdp.rsdt_address as usize, 4)
+        };
+        let root = map_table(PhysPtr::new(root)).expect("acpi: bad root table checksum");
+
+        let tables: Vec<_> = root
+            .data()
+            .chunks_exact(entry_size)
+            .filter_map(|entry| {
+                let mut addr = [0; 8];
+                addr[..entry_size].copy_from_slice(entry);
+                map_table(PhysPtr::new(u64::from_le_bytes(addr) as usize))
+            })
+            .collect();
+
+        for table in &tables {
+            println!(
+                "acpi: found {}",
+                core::str::from_utf8(&table.signature).unwrap_or("????")
+            );
+        }
+        tables
+    });
+}
+
+/// Returns the first table with the given signature
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    TABLES
+        .get()
+        .expect("acpi isn't initialized")
+        .iter()
+        .find(|table| &table.signature == signature)
+        .copied()
+}
@@ -0,0 +1,50 @@
+//! Fixed ACPI description table, only the parts the kernel uses
+
+use spin::Once;
+
+use crate::println;
+
+use super::find_table;
+
+// offsets into the table, after the header
+const CENTURY: usize = 72;
+const BOOT_ARCH_FLAGS: usize = 73;
+
+const CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+#[derive(Debug)]
+pub struct Fadt {
+    /// Cmos index of the rtc century
+    pub century: Option<u8>,
+    pub cmos_rtc: bool,
+}
+
+static FADT: Once<Option<Fadt>> = Once::new();
+
+/// Returns the parsed fadt, or `None` if the firmware doesn't have one
+pub fn get() -> Option<&'static Fadt> {
+    FADT.call_once(|| {
+        let fadt = parse();
+        if fadt.is_none() {
+            println!("acpi: no fadt");
+        }
+        fadt
+    })
+    .as_ref()
+}
+
+fn parse() -> Option<Fadt> {
+    let data = find_table(b"FACP")?.data();
+    if data.len() < BOOT_ARCH_FLAGS + 2 {
+        println!("acpi: malformed fadt");
+        return None;
+    }
+
+    let boot_arch = u16::from_le_bytes(data[BOOT_ARCH_FLAGS..][..2].try_into().unwrap());
+
+    Some(Fadt {
+        // zero means there is no century register
+        century: Some(data[CENTURY]).filter(|&index| index != 0),
+        cmos_rtc: boot_arch & CMOS_RTC_NOT_PRESENT == 0,
+    })
+}
@@ -0,0 +1,62 @@
+//! High Precision Event Timer description table
+
+use spin::Once;
+
+use crate::println;
+
+use super::find_table;
+
+/// Generic address structure space id of system memory
+const SYSTEM_MEMORY: u8 = 0;
+
+#[derive(Debug)]
+pub struct Hpet {
+    /// Physical address of the registers
+    pub address: u64,
+    /// Sequence number of this hpet
+    pub number: u8,
+    /// Main counter ticks below which periodic interrupts may be lost
+    pub minimum_tick: u16,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+}
+
+static HPET: Once<Option<Hpet>> = Once::new();
+
+/// Returns the first hpet, or `None` if the firmware doesn't describe one
+pub fn get() -> Option<&'static Hpet> {
+    HPET.call_once(|| {
+        let hpet = parse();
+        if hpet.is_none() {
+            println!("acpi: no hpet");
+        }
+        hpet
+    })
+    .as_ref()
+}
+
+fn parse() -> Option<Hpet> {
+    let data = find_table(b"HPET")?.data();
+    if data.len() < 20 {
+        println!("acpi: malformed hpet table");
+        return None;
+    }
+
+    let block_id = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    let address_space = data[4];
+    let address = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    if address_space != SYSTEM_MEMORY {
+        println!("acpi: hpet isn't memory mapped");
+        return None;
+    }
+
+    Some(Hpet {
+        address,
+        number: data[16],
+        minimum_tick: u16::from_le_bytes(data[17..19].try_into().unwrap()),
+        comparators: ((block_id >> 8) & 0x1f) as u8 + 1,
+        counter_64bit: block_id & (1 << 13) != 0,
+        legacy_replacement: block_id & (1 << 15) != 0,
+    })
+}
@@ -0,0 +1,199 @@
+//! Multiple APIC Description Table
+
+use alloc::vec::Vec;
+use spin::Once;
+
+use crate::println;
+
+use super::find_table;
+
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+const LOCAL_X2APIC_NMI: u8 = 0xa;
+
+/// The system also has 8259 pics that must be disabled
+const PCAT_COMPAT: u32 = 1 << 0;
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt handled by this io apic
+    pub gsi_base: u32,
+}
+
+/// An isa irq that isn't identity mapped to a gsi, or doesn't use isa defaults
+#[derive(Debug, Clone, Copy)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+/// A local apic lint pin wired to nmi
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// `None` means every processor
+    pub processor_uid: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub has_legacy_pics: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<SourceOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+static MADT: Once<Option<Madt>> = Once::new();
+
+/// Returns the parsed madt, or `None` if the firmware doesn't have one
+pub fn get() -> Option<&'static Madt> {
+    MADT.call_once(|| {
+        let madt = parse();
+        if madt.is_none() {
+            println!("acpi: no madt");
+        }
+        madt
+    })
+    .as_ref()
+}
+
+impl Madt {
+    /// Returns the gsi and flags an isa irq is connected to
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, Trigger) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, o.polarity, o.trigger),
+            None => (irq as u32, Polarity::ActiveHigh, Trigger::Edge),
+        }
+    }
+}
+
+/// Decodes mps inti flags, using isa defaults for "conforms to the bus"
+fn decode_flags(flags: u16) -> (Polarity, Trigger) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => Trigger::Level,
+        _ => Trigger::Edge,
+    };
+    (polarity, trigger)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn parse() -> Option<Madt> {
+    let data = find_table(b"APIC")?.data();
+
+    let mut madt = Madt {
+        local_apic_address: u32_at(data, 0) as u64,
+        has_legacy_pics: u32_at(data, 4) & PCAT_COMPAT != 0,
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+        nmis: Vec::new(),
+    };
+
+    let mut entries = &data[8..];
+    while let [kind, len, ..] = *entries {
+        let len = len as usize;
+        if len < 2 || len > entries.len() {
+            println!("acpi: malformed madt entry");
+            break;
+        }
+        let entry = &entries[..len];
+        entries = &entries[len..];
+
+        match kind {
+            LOCAL_APIC => madt.local_apics.push(LocalApic {
+                processor_uid: entry[2] as u32,
+                apic_id: entry[3] as u32,
+                enabled: u32_at(entry, 4) & PROCESSOR_ENABLED != 0,
+            }),
+            LOCAL_X2APIC => madt.local_apics.push(LocalApic {
+                processor_uid: u32_at(entry, 12),
+                apic_id: u32_at(entry, 4),
+                enabled: u32_at(entry, 8) & PROCESSOR_ENABLED != 0,
+            }),
+            IO_APIC => madt.io_apics.push(IoApic {
+                id: entry[2],
+                address: u32_at(entry, 4),
+                gsi_base: u32_at(entry, 8),
+            }),
+            SOURCE_OVERRIDE => {
+                let (polarity, trigger) = decode_flags(u16_at(entry, 8));
+                madt.overrides.push(SourceOverride {
+                    irq: entry[3],
+                    gsi: u32_at(entry, 4),
+                    polarity,
+                    trigger,
+                });
+            }
+            LOCAL_APIC_NMI => {
+                let (polarity, trigger) = decode_flags(u16_at(entry, 3));
+                madt.nmis.push(LocalApicNmi {
+                    processor_uid: (entry[2] != 0xff).then_some(entry[2] as u32),
+                    lint: entry[5],
+                    polarity,
+                    trigger,
+                });
+            }
+            LOCAL_X2APIC_NMI => {
+                let (polarity, trigger) = decode_flags(u16_at(entry, 2));
+                let uid = u32_at(entry, 4);
+                madt.nmis.push(LocalApicNmi {
+                    processor_uid: (uid != u32::MAX).then_some(uid),
+                    lint: entry[8],
+                    polarity,
+                    trigger,
+                });
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => madt.local_apic_address = u64_at(entry, 4),
+            _ => {}
+        }
+    }
+
+    Some(madt)
+}
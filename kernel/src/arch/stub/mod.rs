@@ -1,14 +1,24 @@
 use std::{
     cell::Cell,
     io::{stdout, Write},
+    ptr,
+    sync::LazyLock,
+    time::{Instant, SystemTime},
 };
 
+use crate::{cpulocal, cpuset::CpuSet, time::DateTime};
+
 thread_local! {
     static CPUID: Cell<u32> = panic!();
+    static INTERRUPTS: Cell<bool> = const { Cell::new(false) };
+    static AREA: Cell<*mut u8> = const { Cell::new(ptr::null_mut()) };
 }
 
+pub const PERCPU_RESERVED: usize = 0;
+
 pub fn init(cpuid: u32) {
     CPUID.set(cpuid);
+    AREA.set(cpulocal::area(cpuid).as_ptr());
 }
 
 pub fn hcf() -> ! {
@@ -19,6 +29,54 @@ pub fn get_cpuid() -> u32 {
     CPUID.get()
 }
 
+pub fn try_get_cpuid() -> Option<u32> {
+    cpulocal::is_area(AREA.get() as usize).then(|| CPUID.get())
+}
+
+pub fn this_cpu_area() -> *mut u8 {
+    AREA.get()
+}
+
+pub fn interrupts_enabled() -> bool {
+    INTERRUPTS.get()
+}
+
+pub fn enable_interrupts() {
+    INTERRUPTS.set(true);
+}
+
+pub fn disable_interrupts() {
+    INTERRUPTS.set(false);
+}
+
+pub fn nanos_since_boot() -> u64 {
+    static BOOT: LazyLock<Instant> = LazyLock::new(Instant::now);
+    BOOT.elapsed().as_nanos() as u64
+}
+
+pub fn read_wall_clock() -> Option<DateTime> {
+    let since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?;
+    Some(DateTime::from_unix(since_epoch.as_secs() as i64))
+}
+
+pub fn frame_pointer() -> usize {
+    0
+}
+
+pub fn run_on_set(cpus: &CpuSet, f: impl Fn() + Send + Sync + 'static) {
+    if cpus.contains(get_cpuid()) {
+        f();
+    }
+}
+
+pub fn request_tlb_flush() {}
+
+pub fn stop_other_cpus() {}
+
+pub fn print_registers() {}
+
 pub fn debug_print(s: &str) {
     stdout().write_all(s.as_bytes()).unwrap();
 }
@@ -0,0 +1,479 @@
+//! Cpu identification and feature detection
+//!
+//! Every cpu queries its own cpuid leaves once while initializing. Features
+//! are only reported as present if every cpu initialized so far has them.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count, CpuidResult},
+    str,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use spin::Once;
+
+use crate::{assert_once_percpu, cpulocal::CpuLocal, println};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CpuFeatures: u64 {
+        // leaf 1 edx
+        const FPU = 1 << 0;
+        const TSC = 1 << 1;
+        const MSR = 1 << 2;
+        const PAE = 1 << 3;
+        const MCE = 1 << 4;
+        const APIC = 1 << 5;
+        const PGE = 1 << 6;
+        const MCA = 1 << 7;
+        const PAT = 1 << 8;
+        const CLFLUSH = 1 << 9;
+        const FXSR = 1 << 10;
+        const SSE = 1 << 11;
+        const SSE2 = 1 << 12;
+        const HTT = 1 << 13;
+        // leaf 1 ecx
+        const SSE3 = 1 << 14;
+        const SSSE3 = 1 << 15;
+        const FMA = 1 << 16;
+        const CX16 = 1 << 17;
+        const PCID = 1 << 18;
+        const SSE4_1 = 1 << 19;
+        const SSE4_2 = 1 << 20;
+        const X2APIC = 1 << 21;
+        const MOVBE = 1 << 22;
+        const POPCNT = 1 << 23;
+        const TSC_DEADLINE = 1 << 24;
+        const XSAVE = 1 << 25;
+        const OSXSAVE = 1 << 26;
+        const AVX = 1 << 27;
+        const F16C = 1 << 28;
+        const RDRAND = 1 << 29;
+        const HYPERVISOR = 1 << 30;
+        // leaf 7 ebx/ecx/edx
+        const FSGSBASE = 1 << 31;
+        const BMI1 = 1 << 32;
+        const AVX2 = 1 << 33;
+        const SMEP = 1 << 34;
+        const BMI2 = 1 << 35;
+        const ERMS = 1 << 36;
+        const INVPCID = 1 << 37;
+        const AVX512F = 1 << 38;
+        const RDSEED = 1 << 39;
+        const SMAP = 1 << 40;
+        const CLFLUSHOPT = 1 << 41;
+        const UMIP = 1 << 42;
+        const PKU = 1 << 43;
+        const CET_SS = 1 << 44;
+        const CET_IBT = 1 << 45;
+        // leaf 0xd subleaf 1 eax
+        const XSAVEOPT = 1 << 46;
+        const XSAVEC = 1 << 47;
+        const XSAVES = 1 << 48;
+        // extended leaves
+        const SYSCALL = 1 << 49;
+        const NX = 1 << 50;
+        const PAGE_1GB = 1 << 51;
+        const RDTSCP = 1 << 52;
+        const LONG_MODE = 1 << 53;
+        const INVARIANT_TSC = 1 << 54;
+    }
+}
+
+/// Features the kernel can't run without
+const REQUIRED: CpuFeatures = CpuFeatures::TSC
+    .union(CpuFeatures::MSR)
+    .union(CpuFeatures::APIC)
+    .union(CpuFeatures::NX)
+    .union(CpuFeatures::LONG_MODE);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cache {
+    pub level: u8,
+    pub kind: CacheKind,
+    pub size: usize,
+    pub line_size: usize,
+    pub ways: usize,
+    /// Maximum number of logical cpus sharing this cache
+    pub shared_by: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Topology {
+    /// The full x2apic id if available, the 8 bit initial apic id otherwise
+    pub apic_id: u32,
+    pub threads_per_core: u32,
+    pub logical_per_package: u32,
+}
+
+#[derive(Debug)]
+pub struct CpuInfo {
+    pub vendor: Vendor,
+    vendor_id: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: CpuFeatures,
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    pub physical_address_bits: u8,
+    pub virtual_address_bits: u8,
+    pub caches: Vec<Cache>,
+    pub topology: Topology,
+}
+
+static INFO: CpuLocal<Once<CpuInfo>> = CpuLocal::new(|_| Once::new());
+/// Features every initialized cpu has
+static COMMON: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Features any initialized cpu has
+static ANY: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn leaf(leaf: u32) -> CpuidResult {
+    unsafe { __cpuid(leaf) }
+}
+
+pub(super) fn subleaf(leaf: u32, subleaf: u32) -> CpuidResult {
+    unsafe { __cpuid_count(leaf, subleaf) }
+}
+
+fn bit(reg: u32, bit: u32) -> bool {
+    reg & (1 << bit) != 0
+}
+
+/// Splits the eax of leaf 1 into the family, model and stepping, adding the
+/// extended fields where they apply
+fn decode_version(eax: u32) -> (u32, u32, u32) {
+    let base_family = (eax >> 8) & 0xf;
+    let mut family = base_family;
+    let mut model = (eax >> 4) & 0xf;
+    if base_family == 0xf {
+        family += (eax >> 20) & 0xff;
+    }
+    if base_family == 0x6 || base_family == 0xf {
+        model += ((eax >> 16) & 0xf) << 4;
+    }
+    (family, model, eax & 0xf)
+}
+
+impl CpuInfo {
+    fn query() -> Self {
+        let base = leaf(0);
+        let max_leaf = base.eax;
+        let mut vendor_id = [0; 12];
+        vendor_id[0..4].copy_from_slice(&base.ebx.to_le_bytes());
+        vendor_id[4..8].copy_from_slice(&base.edx.to_le_bytes());
+        vendor_id[8..12].copy_from_slice(&base.ecx.to_le_bytes());
+        let vendor = match &vendor_id {
+            b"GenuineIntel" => Vendor::Intel,
+            b"AuthenticAMD" => Vendor::Amd,
+            _ => Vendor::Other,
+        };
+        let max_extended_leaf = leaf(0x8000_0000).eax;
+
+        let (family, model, stepping) = decode_version(leaf(1).eax);
+
+        let mut brand = [0; 48];
+        if max_extended_leaf >= 0x8000_0004 {
+            for (i, chunk) in brand.chunks_exact_mut(16).enumerate() {
+                let regs = leaf(0x8000_0002 + i as u32);
+                for (j, reg) in [regs.eax, regs.ebx, regs.ecx, regs.edx].iter().enumerate() {
+                    chunk[j * 4..j * 4 + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+        }
+
+        let (physical_address_bits, virtual_address_bits) = if max_extended_leaf >= 0x8000_0008 {
+            let sizes = leaf(0x8000_0008).eax;
+            (sizes as u8, (sizes >> 8) as u8)
+        } else {
+            (36, 48)
+        };
+
+        Self {
+            vendor,
+            vendor_id,
+            brand,
+            family,
+            model,
+            stepping,
+            features: query_features(max_leaf, max_extended_leaf),
+            max_leaf,
+            max_extended_leaf,
+            physical_address_bits,
+            virtual_address_bits,
+            caches: query_caches(vendor, max_leaf, max_extended_leaf),
+            topology: query_topology(max_leaf),
+        }
+    }
+
+    pub fn vendor_id(&self) -> &str {
+        str::from_utf8(&self.vendor_id).unwrap_or("unknown")
+    }
+
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&b| b == 0).unwrap_or(48);
+        str::from_utf8(&self.brand[..len])
+            .unwrap_or("unknown")
+            .trim()
+    }
+}
+
+fn query_features(max_leaf: u32, max_extended_leaf: u32) -> CpuFeatures {
+    let mut features = CpuFeatures::empty();
+    let mut set = |feature, present| features.set(feature, present);
+
+    let version = leaf(1);
+    let (ecx, edx) = (version.ecx, version.edx);
+    set(CpuFeatures::FPU, bit(edx, 0));
+    set(CpuFeatures::TSC, bit(edx, 4));
+    set(CpuFeatures::MSR, bit(edx, 5));
+    set(CpuFeatures::PAE, bit(edx, 6));
+    set(CpuFeatures::MCE, bit(edx, 7));
+    set(CpuFeatures::APIC, bit(edx, 9));
+    set(CpuFeatures::PGE, bit(edx, 13));
+    set(CpuFeatures::MCA, bit(edx, 14));
+    set(CpuFeatures::PAT, bit(edx, 16));
+    set(CpuFeatures::CLFLUSH, bit(edx, 19));
+    set(CpuFeatures::FXSR, bit(edx, 24));
+    set(CpuFeatures::SSE, bit(edx, 25));
+    set(CpuFeatures::SSE2, bit(edx, 26));
+    set(CpuFeatures::HTT, bit(edx, 28));
+    set(CpuFeatures::SSE3, bit(ecx, 0));
+    set(CpuFeatures::SSSE3, bit(ecx, 9));
+    set(CpuFeatures::FMA, bit(ecx, 12));
+    set(CpuFeatures::CX16, bit(ecx, 13));
+    set(CpuFeatures::PCID, bit(ecx, 17));
+    set(CpuFeatures::SSE4_1, bit(ecx, 19));
+    set(CpuFeatures::SSE4_2, bit(ecx, 20));
+    set(CpuFeatures::X2APIC, bit(ecx, 21));
+    set(CpuFeatures::MOVBE, bit(ecx, 22));
+    set(CpuFeatures::POPCNT, bit(ecx, 23));
+    set(CpuFeatures::TSC_DEADLINE, bit(ecx, 24));
+    set(CpuFeatures::XSAVE, bit(ecx, 26));
+    set(CpuFeatures::OSXSAVE, bit(ecx, 27));
+    set(CpuFeatures::AVX, bit(ecx, 28));
+    set(CpuFeatures::F16C, bit(ecx, 29));
+    set(CpuFeatures::RDRAND, bit(ecx, 30));
+    set(CpuFeatures::HYPERVISOR, bit(ecx, 31));
+
+    if max_leaf >= 7 {
+        let extended = subleaf(7, 0);
+        let (ebx, ecx, edx) = (extended.ebx, extended.ecx, extended.edx);
+        set(CpuFeatures::FSGSBASE, bit(ebx, 0));
+        set(CpuFeatures::BMI1, bit(ebx, 3));
+        set(CpuFeatures::AVX2, bit(ebx, 5));
+        set(CpuFeatures::SMEP, bit(ebx, 7));
+        set(CpuFeatures::BMI2, bit(ebx, 8));
+        set(CpuFeatures::ERMS, bit(ebx, 9));
+        set(CpuFeatures::INVPCID, bit(ebx, 10));
+        set(CpuFeatures::AVX512F, bit(ebx, 16));
+        set(CpuFeatures::RDSEED, bit(ebx, 18));
+        set(CpuFeatures::SMAP, bit(ebx, 20));
+        set(CpuFeatures::CLFLUSHOPT, bit(ebx, 23));
+        set(CpuFeatures::UMIP, bit(ecx, 2));
+        set(CpuFeatures::PKU, bit(ecx, 3));
+        set(CpuFeatures::CET_SS, bit(ecx, 7));
+        set(CpuFeatures::CET_IBT, bit(edx, 20));
+    }
+
+    if max_leaf >= 0xd {
+        let xsave = subleaf(0xd, 1).eax;
+        set(CpuFeatures::XSAVEOPT, bit(xsave, 0));
+        set(CpuFeatures::XSAVEC, bit(xsave, 1));
+        set(CpuFeatures::XSAVES, bit(xsave, 3));
+    }
+
+    if max_extended_leaf >= 0x8000_0001 {
+        let edx = leaf(0x8000_0001).edx;
+        set(CpuFeatures::SYSCALL, bit(edx, 11));
+        set(CpuFeatures::NX, bit(edx, 20));
+        set(CpuFeatures::PAGE_1GB, bit(edx, 26));
+        set(CpuFeatures::RDTSCP, bit(edx, 27));
+        set(CpuFeatures::LONG_MODE, bit(edx, 29));
+    }
+
+    if max_extended_leaf >= 0x8000_0007 {
+        set(CpuFeatures::INVARIANT_TSC, bit(leaf(0x8000_0007).edx, 8));
+    }
+
+    features
+}
+
+/// Reads the deterministic cache parameters, leaf 4 on intel and 0x8000001d on amd
+fn query_caches(vendor: Vendor, max_leaf: u32, max_extended_leaf: u32) -> Vec<Cache> {
+    let cache_leaf = match vendor {
+        Vendor::Amd if max_extended_leaf >= 0x8000_001d && bit(leaf(0x8000_0001).ecx, 22) => {
+            0x8000_001d
+        }
+        Vendor::Intel if max_leaf >= 4 => 4,
+        _ => return Vec::new(),
+    };
+
+    (0..)
+        .map(|index| subleaf(cache_leaf, index))
+        .map_while(|regs| {
+            let kind = match regs.eax & 0x1f {
+                1 => CacheKind::Data,
+                2 => CacheKind::Instruction,
+                3 => CacheKind::Unified,
+                _ => return None,
+            };
+            let line_size = (regs.ebx & 0xfff) as usize + 1;
+            let partitions = ((regs.ebx >> 12) & 0x3ff) as usize + 1;
+            let ways = (regs.ebx >> 22) as usize + 1;
+            let sets = regs.ecx as usize + 1;
+
+            Some(Cache {
+                level: ((regs.eax >> 5) & 0x7) as u8,
+                kind,
+                size: ways * partitions * line_size * sets,
+                line_size,
+                ways,
+                shared_by: ((regs.eax >> 14) & 0xfff) + 1,
+            })
+        })
+        .collect()
+}
+
+fn query_topology(max_leaf: u32) -> Topology {
+    const LEVEL_SMT: u32 = 1;
+    const LEVEL_CORE: u32 = 2;
+
+    let version = leaf(1);
+    let mut topology = Topology {
+        apic_id: version.ebx >> 24,
+        threads_per_core: 1,
+        logical_per_package: if bit(version.edx, 28) {
+            (version.ebx >> 16) & 0xff
+        } else {
+            1
+        },
+    };
+
+    if max_leaf >= 0xb {
+        for level in 0.. {
+            let regs = subleaf(0xb, level);
+            let count = regs.ebx & 0xffff;
+            match (regs.ecx >> 8) & 0xff {
+                LEVEL_SMT => topology.threads_per_core = count,
+                LEVEL_CORE => topology.logical_per_package = count,
+                _ => break,
+            }
+            topology.apic_id = regs.edx;
+        }
+    }
+
+    topology
+}
+
+/// Queries this cpu's cpuid leaves, the first thing every cpu does
+pub fn init(cpuid: u32) {
+    assert_once_percpu!(cpuid);
+
+    let info = INFO.get(cpuid).call_once(CpuInfo::query);
+    let features = info.features.bits();
+
+    let missing = REQUIRED.difference(info.features);
+    assert!(missing.is_empty(), "cpu {cpuid} is missing {missing:?}");
+
+    let common = COMMON.fetch_and(features, Ordering::AcqRel);
+    ANY.fetch_or(features, Ordering::AcqRel);
+    if common != u64::MAX && common != features {
+        println!(
+            "cpuid: cpu {cpuid} differs from the others in {:?}",
+            CpuFeatures::from_bits_truncate(common ^ features)
+        );
+    }
+
+    if cpuid == 0 {
+        print_summary(info);
+    }
+}
+
+fn print_summary(info: &CpuInfo) {
+    println!(
+        "cpuid: {} ({}), family {:#x} model {:#x} stepping {}",
+        info.brand(),
+        info.vendor_id(),
+        info.family,
+        info.model,
+        info.stepping
+    );
+    println!(
+        "cpuid: {} bit physical, {} bit virtual addresses",
+        info.physical_address_bits, info.virtual_address_bits
+    );
+    println!(
+        "cpuid: {} threads per core, {} logical cpus per package",
+        info.topology.threads_per_core, info.topology.logical_per_package
+    );
+    for cache in &info.caches {
+        println!(
+            "cpuid: L{} {:?} cache, {} KiB, {} way, {} byte lines, shared by {}",
+            cache.level,
+            cache.kind,
+            cache.size / 1024,
+            cache.ways,
+            cache.line_size,
+            cache.shared_by
+        );
+    }
+    println!("cpuid: {:?}", info.features);
+}
+
+/// Returns the current cpu's info
+pub fn info() -> &'static CpuInfo {
+    info_of(super::get_cpuid())
+}
+
+pub fn info_of(cpuid: u32) -> &'static CpuInfo {
+    INFO.get(cpuid).get().expect("cpu hasn't queried cpuid yet")
+}
+
+/// Returns the features every cpu initialized so far has
+pub fn features() -> CpuFeatures {
+    CpuFeatures::from_bits_truncate(COMMON.load(Ordering::Acquire))
+}
+
+/// Returns the features only some cpus have
+pub fn heterogeneous_features() -> CpuFeatures {
+    let common = COMMON.load(Ordering::Acquire);
+    let any = ANY.load(Ordering::Acquire);
+    CpuFeatures::from_bits_truncate(any & !common)
+}
+
+pub fn has(feature: CpuFeatures) -> bool {
+    features().contains(feature)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn version() {
+        // skylake, extended model only
+        assert_eq!(decode_version(0x0005_06e3), (0x6, 0x5e, 3));
+        // zen 2 and 4, extended family and model
+        assert_eq!(decode_version(0x0087_0f10), (0x17, 0x71, 0));
+        assert_eq!(decode_version(0x00a6_0f12), (0x19, 0x61, 2));
+        // the extended model only counts for families 6 and 15
+        assert_eq!(decode_version(0x0001_0543), (0x5, 0x4, 3));
+    }
+}
@@ -0,0 +1,350 @@
+//! Lazy saving of the x87, sse and avx registers
+//!
+//! The kernel is built without simd, so the registers only hold user state, or
+//! kernel state while a [KernelFpu] guard is alive. [switch_to] only sets
+//! CR0.TS, the first simd instruction afterwards raises #NM, which saves the
+//! previous state and loads the new one.
+
+use core::{
+    alloc::Layout,
+    arch::asm,
+    hint,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
+use spin::Once;
+
+use crate::{assert_once_percpu, boot, cpulocal::CpuLocal, println, sync::InterruptGuard};
+
+use super::{
+    cpuid::{self, CpuFeatures},
+    interrupts,
+    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags, Xcr0, Xcr0Flags},
+};
+
+const DEVICE_NOT_AVAILABLE: u8 = 7;
+
+/// Size of the legacy region, all fxsave needs
+const FXSAVE_SIZE: usize = 512;
+/// The legacy region and the xsave header
+const XSAVE_MIN_SIZE: usize = 576;
+/// xsave areas must be aligned to this
+const ALIGN: usize = 64;
+
+/// Default control words, with every exception masked
+const DEFAULT_FCW: u16 = 0x37f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+const MXCSR_OFFSET: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mechanism {
+    Fxsave,
+    Xsave,
+    XsaveOpt,
+}
+
+#[derive(Debug)]
+struct Config {
+    mechanism: Mechanism,
+    xcr0: Xcr0Flags,
+    /// Size of a state area
+    size: usize,
+}
+
+/// Saved simd registers of a thread
+pub struct FpuState {
+    area: NonNull<u8>,
+}
+
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+static CONFIG: Once<Config> = Once::new();
+
+/// State of the thread running on each cpu
+static CURRENT: CpuLocal<AtomicPtr<FpuState>> = CpuLocal::new(|_| AtomicPtr::new(ptr::null_mut()));
+/// State the registers of each cpu hold
+static LOADED: CpuLocal<AtomicPtr<FpuState>> = CpuLocal::new(|_| AtomicPtr::new(ptr::null_mut()));
+static IN_KERNEL: CpuLocal<AtomicBool> = CpuLocal::new(|_| AtomicBool::new(false));
+/// Stored in [LOADED] while a cpu saves its registers, so dropping the state
+/// waits until it's done
+const SAVING: *mut FpuState = ptr::dangling_mut();
+
+fn config() -> &'static Config {
+    CONFIG.get().expect("fpu isn't initialized")
+}
+
+fn layout() -> Layout {
+    Layout::from_size_align(config().size, ALIGN).unwrap()
+}
+
+/// Picks the state components to enable, from the first cpu to initialize
+fn query(cpuid: u32) -> Config {
+    let features = cpuid::info_of(cpuid).features;
+    if !features.contains(CpuFeatures::XSAVE) {
+        return Config {
+            mechanism: Mechanism::Fxsave,
+            xcr0: Xcr0Flags::empty(),
+            size: FXSAVE_SIZE,
+        };
+    }
+
+    let supported = Xcr0Flags::from_bits_truncate(cpuid::subleaf(0xd, 0).eax as u64);
+    let mut xcr0 = Xcr0Flags::X87 | Xcr0Flags::SSE;
+    if features.contains(CpuFeatures::AVX) {
+        xcr0 |= supported & Xcr0Flags::AVX;
+    }
+    let avx512 = Xcr0Flags::OPMASK | Xcr0Flags::ZMM_HI256 | Xcr0Flags::HI16_ZMM;
+    if features.contains(CpuFeatures::AVX512F) && supported.contains(avx512) {
+        xcr0 |= avx512;
+    }
+
+    // components past sse report their size and offset in the standard layout
+    let size = (2..64)
+        .filter(|&i| xcr0.bits() & (1 << i) != 0)
+        .map(|i| {
+            let component = cpuid::subleaf(0xd, i);
+            (component.ebx + component.eax) as usize
+        })
+        .fold(XSAVE_MIN_SIZE, usize::max);
+
+    let mechanism = if features.contains(CpuFeatures::XSAVEOPT) {
+        Mechanism::XsaveOpt
+    } else {
+        Mechanism::Xsave
+    };
+
+    Config {
+        mechanism,
+        xcr0,
+        size,
+    }
+}
+
+/// Enables sse and xsave on this cpu, with CR0.TS set
+pub fn init(cpuid: u32) {
+    assert_once_percpu!(cpuid);
+
+    let config = CONFIG.call_once(|| {
+        let config = query(cpuid);
+        println!(
+            "fpu: using {:?}, {} byte states, {:?}",
+            config.mechanism, config.size, config.xcr0
+        );
+        config
+    });
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(
+                Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR | Cr0Flags::TASK_SWITCHED,
+            );
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT);
+            if config.mechanism != Mechanism::Fxsave {
+                flags.insert(Cr4Flags::OSXSAVE);
+            }
+        });
+    }
+
+    if config.mechanism != Mechanism::Fxsave {
+        let supported = Xcr0Flags::from_bits_truncate(cpuid::subleaf(0xd, 0).eax as u64);
+        assert!(
+            supported.contains(config.xcr0),
+            "cpu {cpuid} doesn't support {:?}",
+            config.xcr0.difference(supported)
+        );
+        unsafe { Xcr0::write(config.xcr0) };
+    }
+
+    if cpuid == 0 {
+        interrupts::register_handler(DEVICE_NOT_AVAILABLE, |_| device_not_available());
+    }
+}
+
+/// Loads the current thread's state, returns false if there is none
+fn device_not_available() -> bool {
+    // handlers run with interrupts disabled
+    let current = unsafe { CURRENT.force() }.load(Ordering::Relaxed);
+    if current.is_null() {
+        return false;
+    }
+
+    unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED)) };
+    let loaded = unsafe { LOADED.force() };
+    if loaded.load(Ordering::Relaxed) != current {
+        unsafe {
+            save_loaded(loaded, current);
+            (*current).restore();
+        }
+    }
+    true
+}
+
+/// Saves the state this cpu's registers hold, and records `next` as loaded
+///
+/// # Safety
+/// CR0.TS must be clear, and interrupts disabled
+unsafe fn save_loaded(loaded: &AtomicPtr<FpuState>, next: *mut FpuState) {
+    // another cpu dropping the state can forget it until it's marked as saving
+    let mut previous = loaded.load(Ordering::Relaxed);
+    while let Err(actual) =
+        loaded.compare_exchange_weak(previous, SAVING, Ordering::Acquire, Ordering::Relaxed)
+    {
+        previous = actual;
+    }
+
+    if let Some(previous) = unsafe { previous.as_mut() } {
+        unsafe { previous.save() };
+    }
+    loaded.store(next, Ordering::Release);
+}
+
+/// Makes `state` the one simd instructions on this cpu use, called when
+/// switching threads
+///
+/// # Safety
+/// `state` must stay alive until the next switch, null means simd is unusable
+pub unsafe fn switch_to(state: *mut FpuState) {
+    interrupts::without_interrupts(|| {
+        let (current, loaded) = unsafe { (CURRENT.force(), LOADED.force()) };
+        current.store(state, Ordering::Relaxed);
+        let loaded = loaded.load(Ordering::Relaxed) == state;
+        unsafe {
+            Cr0::update(|flags| flags.set(Cr0Flags::TASK_SWITCHED, !loaded || state.is_null()))
+        };
+    });
+}
+
+impl FpuState {
+    /// Returns a state with every register in its initial state
+    pub fn new() -> Self {
+        let layout = layout();
+        let Some(area) = NonNull::new(unsafe { alloc_zeroed(layout) }) else {
+            handle_alloc_error(layout);
+        };
+
+        // a zeroed xsave header already means the initial state, except for mxcsr
+        unsafe {
+            area.cast::<u16>().write(DEFAULT_FCW);
+            area.byte_add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write(DEFAULT_MXCSR);
+        }
+        Self { area }
+    }
+
+    /// # Safety
+    /// CR0.TS must be clear
+    unsafe fn save(&mut self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            match config().mechanism {
+                Mechanism::Fxsave => {
+                    asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags))
+                }
+                Mechanism::Xsave => asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack, preserves_flags),
+                ),
+                Mechanism::XsaveOpt => asm!(
+                    "xsaveopt64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack, preserves_flags),
+                ),
+            }
+        }
+    }
+
+    /// # Safety
+    /// CR0.TS must be clear
+    unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            match config().mechanism {
+                Mechanism::Fxsave => {
+                    asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags))
+                }
+                Mechanism::Xsave | Mechanism::XsaveOpt => asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack, preserves_flags),
+                ),
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // the registers of another cpu may still hold this state, or be saved
+        // into it right now
+        let this: *mut FpuState = self;
+        for cpuid in 0..boot::cpu_count() {
+            let loaded = LOADED.get(cpuid);
+            while loaded.compare_exchange(
+                this,
+                ptr::null_mut(),
+                Ordering::Relaxed,
+                Ordering::Acquire,
+            ) == Err(SAVING)
+            {
+                hint::spin_loop();
+            }
+        }
+        unsafe { dealloc(self.area.as_ptr(), layout()) };
+    }
+}
+
+/// Lets kernel code use simd registers until dropped, see [kernel_fpu_begin]
+pub struct KernelFpu {
+    /// Restores interrupts after the registers are given back
+    _interrupts: InterruptGuard,
+}
+
+/// Saves the loaded state and disables interrupts, so kernel code can use simd
+/// instructions until the returned guard is dropped
+///
+/// Functions using simd need `#[target_feature]`, since the kernel is built
+/// without it.
+pub fn kernel_fpu_begin() -> KernelFpu {
+    let interrupts = InterruptGuard::new();
+
+    assert!(
+        !unsafe { IN_KERNEL.force() }.swap(true, Ordering::Relaxed),
+        "kernel_fpu_begin can't be nested"
+    );
+
+    unsafe {
+        Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED));
+        save_loaded(LOADED.force(), ptr::null_mut());
+        asm!("fninit", "ldmxcsr [{}]", in(reg) &DEFAULT_MXCSR, options(nostack));
+    }
+
+    KernelFpu {
+        _interrupts: interrupts,
+    }
+}
+
+impl Drop for KernelFpu {
+    fn drop(&mut self) {
+        // the next user of the registers has to load its state again
+        unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+        // the guard still holds interrupts off
+        unsafe { IN_KERNEL.force() }.store(false, Ordering::Relaxed);
+    }
+}
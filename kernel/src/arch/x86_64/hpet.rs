@@ -0,0 +1,368 @@
+//! High Precision Event Timer driver
+//!
+//! The main counter is a clock source that doesn't depend on the cpu, and each
+//! comparator can fire a one shot or periodic interrupt, delivered either as an
+//! msi straight to a local apic or through the io apic.
+
+use core::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+
+use crate::{
+    acpi::{
+        self,
+        madt::{Polarity, Trigger},
+    },
+    boot,
+    mem::{mmio, PhysPtr},
+    println,
+};
+
+use super::{
+    interrupts::{self, HandlerId, IsrData},
+    ioapic::{self, RouteError},
+};
+
+const CAPABILITIES: usize = 0x000;
+const CONFIG: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const TIMER_CONFIG: usize = 0x100;
+const TIMER_COMPARATOR: usize = 0x108;
+const TIMER_FSB_ROUTE: usize = 0x110;
+const TIMER_STRIDE: usize = 0x20;
+const REGS_SIZE: usize = 0x400;
+
+const COUNTER_64BIT: u64 = 1 << 13;
+
+const ENABLE: u64 = 1 << 0;
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// The comparator is 64 bits wide, even on a 64 bit counter some aren't
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5;
+/// Lets the next comparator write set the periodic accumulator
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_FSB_CAPABLE: u64 = 1 << 15;
+const TIMER_ROUTE_CAPABILITIES_SHIFT: u64 = 32;
+
+/// Msi address of a local apic, the apic id goes in bits 12..20
+const MSI_ADDRESS: u32 = 0xfee0_0000;
+
+/// Femtoseconds per nanosecond
+const FS_PER_NS: u64 = 1_000_000;
+
+struct Hpet {
+    regs: *mut u64,
+    /// Main counter period, in femtoseconds
+    period_fs: u64,
+    comparators: u8,
+    /// A 32 bit counter wraps within minutes, [counter] extends it
+    counter_64bit: bool,
+}
+
+unsafe impl Send for Hpet {}
+unsafe impl Sync for Hpet {}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HpetError {
+    NotPresent,
+    /// Every comparator is in use
+    NoFreeTimer,
+    NoFreeVector,
+    /// The comparator can't interrupt through msi or any io apic input
+    NoRoute,
+    PeriodicUnsupported,
+    Route(RouteError),
+}
+
+static HPET: Once<Option<Hpet>> = Once::new();
+/// One bit per comparator, set if it is in use
+static ALLOCATED: AtomicU32 = AtomicU32::new(0);
+/// Last value [counter] returned, for extending a 32 bit counter
+static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl Hpet {
+    fn read(&self, reg: usize) -> u64 {
+        unsafe { self.regs.byte_add(reg).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, val: u64) {
+        unsafe { self.regs.byte_add(reg).write_volatile(val) }
+    }
+
+    fn timer_reg(index: u8, reg: usize) -> usize {
+        reg + index as usize * TIMER_STRIDE
+    }
+}
+
+fn hpet() -> Result<&'static Hpet, HpetError> {
+    HPET.get()
+        .expect("hpet isn't initialized")
+        .as_ref()
+        .ok_or(HpetError::NotPresent)
+}
+
+/// Finds the hpet and starts its main counter, called once on the bsp
+pub fn init() {
+    crate::assert_once!();
+
+    HPET.call_once(|| {
+        let info = acpi::hpet::get()?;
+        let regs = mmio::map_mmio(PhysPtr::<u64>::new(info.address as usize), REGS_SIZE)
+            .expect("critical mapping failed");
+        let mut hpet = Hpet {
+            regs,
+            period_fs: 0,
+            comparators: 0,
+            counter_64bit: false,
+        };
+
+        let capabilities = hpet.read(CAPABILITIES);
+        hpet.period_fs = capabilities >> 32;
+        hpet.comparators = ((capabilities >> 8) & 0x1f) as u8 + 1;
+        hpet.counter_64bit = capabilities & COUNTER_64BIT != 0;
+        if hpet.period_fs == 0 {
+            println!("hpet: invalid period");
+            return None;
+        }
+
+        // stop it while resetting, and leave the legacy pit and rtc irqs alone
+        let config = hpet.read(CONFIG) & !(ENABLE | LEGACY_REPLACEMENT);
+        hpet.write(CONFIG, config);
+        hpet.write(MAIN_COUNTER, 0);
+        for index in 0..hpet.comparators {
+            let reg = Hpet::timer_reg(index, TIMER_CONFIG);
+            hpet.write(
+                reg,
+                hpet.read(reg) & !(TIMER_INTERRUPT_ENABLE | TIMER_FSB_ENABLE),
+            );
+        }
+        hpet.write(CONFIG, config | ENABLE);
+
+        println!(
+            "hpet: {} MHz, {} comparators, {} bit counter at {:#x}",
+            1_000_000_000 / hpet.period_fs,
+            hpet.comparators,
+            if hpet.counter_64bit { 64 } else { 32 },
+            info.address
+        );
+        Some(hpet)
+    });
+}
+
+pub fn is_available() -> bool {
+    HPET.get().is_some_and(|hpet| hpet.is_some())
+}
+
+/// Reads the main counter, or `None` without an hpet
+///
+/// A 32 bit counter is extended to 64 bits, which only works if this is called
+/// at least once per wraparound, every few minutes.
+pub fn counter() -> Option<u64> {
+    let hpet = hpet().ok()?;
+    if hpet.counter_64bit {
+        return Some(hpet.read(MAIN_COUNTER));
+    }
+
+    let low = hpet.read(MAIN_COUNTER) & u32::MAX as u64;
+    let mut last = LAST_COUNTER.load(Ordering::Acquire);
+    loop {
+        let mut next = (last & !(u32::MAX as u64)) | low;
+        if next < last {
+            next += 1 << 32;
+        }
+        match LAST_COUNTER.compare_exchange_weak(last, next, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return Some(next),
+            // another cpu read a later value meanwhile, ours is still good
+            Err(newer) if newer >= next => return Some(next),
+            Err(newer) => last = newer,
+        }
+    }
+}
+
+/// Returns the main counter frequency in Hz, or `None` without an hpet
+pub fn frequency() -> Option<u64> {
+    Some(1_000_000_000_000_000 / hpet().ok()?.period_fs)
+}
+
+/// Converts main counter ticks into nanoseconds
+pub fn ticks_to_nanos(ticks: u64) -> Option<u64> {
+    Some((ticks as u128 * hpet().ok()?.period_fs as u128 / FS_PER_NS as u128) as u64)
+}
+
+fn duration_to_ticks(hpet: &Hpet, duration: Duration, max_ticks: u64) -> u64 {
+    let ticks = duration.as_nanos() * FS_PER_NS as u128 / hpet.period_fs as u128;
+    ticks.clamp(1, max_ticks as u128) as u64
+}
+
+/// A comparator, with an interrupt routed to one cpu
+///
+/// Dropping it stops the comparator and removes its handler.
+pub struct Timer {
+    index: u8,
+    vector: u8,
+    handler: HandlerId,
+    /// The io apic input it is routed through, if it doesn't use msi
+    gsi: Option<u32>,
+    /// Largest number of ticks the comparator can count ahead
+    max_ticks: u64,
+}
+
+impl Timer {
+    /// Reserves a comparator and routes its interrupt to `handler` on the cpu
+    /// with the given cpuid
+    pub fn new(
+        cpuid: u32,
+        handler: impl Fn(&mut IsrData) -> bool + Send + Sync + 'static,
+    ) -> Result<Self, HpetError> {
+        let hpet = hpet()?;
+
+        let index = (0..hpet.comparators)
+            .find(|&index| {
+                let bit = 1 << index;
+                ALLOCATED.fetch_or(bit, Ordering::AcqRel) & bit == 0
+            })
+            .ok_or(HpetError::NoFreeTimer)?;
+        let release = || ALLOCATED.fetch_and(!(1 << index), Ordering::AcqRel);
+
+        let Some(vector) = interrupts::allocate_vector() else {
+            release();
+            return Err(HpetError::NoFreeVector);
+        };
+
+        let gsi = match route(hpet, index, vector, cpuid) {
+            Ok(gsi) => gsi,
+            Err(err) => {
+                interrupts::free_vector(vector);
+                release();
+                return Err(err);
+            }
+        };
+
+        let config = hpet.read(Hpet::timer_reg(index, TIMER_CONFIG));
+        let max_ticks = if hpet.counter_64bit && config & TIMER_64BIT_CAPABLE != 0 {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+
+        Ok(Self {
+            index,
+            vector,
+            handler: interrupts::register_handler(vector, handler),
+            gsi,
+            max_ticks,
+        })
+    }
+
+    fn config_reg(&self) -> usize {
+        Hpet::timer_reg(self.index, TIMER_CONFIG)
+    }
+
+    fn comparator_reg(&self) -> usize {
+        Hpet::timer_reg(self.index, TIMER_COMPARATOR)
+    }
+
+    /// Fires once after `delay`
+    ///
+    /// Delays shorter than the time the register writes take are missed.
+    pub fn set_oneshot(&self, delay: Duration) {
+        let hpet = hpet().unwrap();
+        let config = hpet.read(self.config_reg()) & !(TIMER_PERIODIC | TIMER_32BIT);
+        hpet.write(self.config_reg(), config & !TIMER_INTERRUPT_ENABLE);
+        hpet.write(
+            self.comparator_reg(),
+            hpet.read(MAIN_COUNTER)
+                .wrapping_add(duration_to_ticks(hpet, delay, self.max_ticks)),
+        );
+        hpet.write(self.config_reg(), config | TIMER_INTERRUPT_ENABLE);
+    }
+
+    /// Fires every `period`, if the comparator supports it
+    pub fn set_periodic(&self, period: Duration) -> Result<(), HpetError> {
+        let hpet = hpet().unwrap();
+        let config = hpet.read(self.config_reg()) & !TIMER_32BIT;
+        if config & TIMER_PERIODIC_CAPABLE == 0 {
+            return Err(HpetError::PeriodicUnsupported);
+        }
+
+        let ticks = duration_to_ticks(hpet, period, self.max_ticks);
+        hpet.write(self.config_reg(), config & !TIMER_INTERRUPT_ENABLE);
+        hpet.write(self.config_reg(), config | TIMER_PERIODIC | TIMER_VALUE_SET);
+        // the first write sets the comparator, the second the period
+        hpet.write(
+            self.comparator_reg(),
+            hpet.read(MAIN_COUNTER).wrapping_add(ticks),
+        );
+        hpet.write(self.comparator_reg(), ticks);
+        hpet.write(
+            self.config_reg(),
+            config | TIMER_PERIODIC | TIMER_INTERRUPT_ENABLE,
+        );
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        let hpet = hpet().unwrap();
+        let config = hpet.read(self.config_reg());
+        hpet.write(
+            self.config_reg(),
+            config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC),
+        );
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(gsi) = self.gsi {
+            let _ = ioapic::mask(gsi);
+        }
+        interrupts::unregister_handler(self.handler);
+        interrupts::free_vector(self.vector);
+        ALLOCATED.fetch_and(!(1 << self.index), Ordering::AcqRel);
+    }
+}
+
+/// Points a comparator's interrupt at `vector` on a cpu, with msi if it can
+///
+/// Returns the io apic input used, if any
+fn route(hpet: &Hpet, index: u8, vector: u8, cpuid: u32) -> Result<Option<u32>, HpetError> {
+    let reg = Hpet::timer_reg(index, TIMER_CONFIG);
+    let config = hpet.read(reg) & !(TIMER_FSB_ENABLE | (0x1f << TIMER_ROUTE_SHIFT));
+
+    let apic_id = boot::apic_id(cpuid);
+    if config & TIMER_FSB_CAPABLE != 0 && apic_id <= 0xff {
+        let address = MSI_ADDRESS | (apic_id << 12);
+        hpet.write(
+            Hpet::timer_reg(index, TIMER_FSB_ROUTE),
+            ((address as u64) << 32) | vector as u64,
+        );
+        hpet.write(reg, config | TIMER_FSB_ENABLE);
+        return Ok(None);
+    }
+
+    // isa irqs are usually taken, prefer the inputs above them
+    let inputs = (config >> TIMER_ROUTE_CAPABILITIES_SHIFT) as u32;
+    let gsi = (16..32)
+        .chain(0..16)
+        .find(|&gsi| inputs & (1 << gsi) != 0)
+        .ok_or(HpetError::NoRoute)?;
+
+    ioapic::route_gsi(gsi, vector, cpuid, Polarity::ActiveHigh, Trigger::Edge)
+        .map_err(HpetError::Route)?;
+    hpet.write(reg, config | ((gsi as u64) << TIMER_ROUTE_SHIFT));
+    Ok(Some(gsi))
+}
@@ -0,0 +1,467 @@
+//! Interrupt dispatch and handler registration
+//!
+//! Every vector has a list of handlers. When an interrupt arrives all of them
+//! are called in registration order, so several devices can share a vector.
+//! A handler returns true if the interrupt was meant for it. Handlers get the
+//! saved state of the interrupted code and may modify it, it's restored by
+//! `iretq` once they return.
+//!
+//! If no handler claims an exception the kernel panics, unclaimed device
+//! interrupts are only reported.
+
+use core::{
+    arch::asm,
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use bitflags::bitflags;
+use spin::RwLock;
+
+use crate::{
+    backtrace,
+    cpulocal::CpuLocal,
+    mem::{Mapper, MappingKind, KERNEL_MAPPER},
+    print, println, stack,
+    sync::InterruptGuard,
+};
+
+use super::{
+    lapic,
+    registers::{control::Cr2, rflags::RFlags},
+    structures::gdt::KERNEL_CODE,
+    try_get_cpuid, user,
+};
+
+/// Vectors handed out by [allocate_vector]
+///
+/// Everything below is reserved for exceptions and legacy irqs,
+/// everything above for the kernel's own interrupts
+pub const DYNAMIC_VECTORS: Range<u8> = 48..0xf0;
+
+pub const EXCEPTIONS: [&str; 32] = [
+    "division error",
+    "debug exception",
+    "non maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid tss",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "",
+    "x87 floating point exception",
+    "alignment check",
+    "machine check",
+    "simd floating point exception",
+    "virtualiztion exception",
+    "control protection exception",
+    "",
+    "",
+    "",
+    "",
+    "",
+    "",
+    "hypervisor injection exception",
+    "vmm communication exception",
+    "security exception",
+    "",
+];
+
+/// General purpose registers, in the order isr.asm pushes them
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: usize,
+    pub rbx: usize,
+    pub rcx: usize,
+    pub rdx: usize,
+    pub rsi: usize,
+    pub rdi: usize,
+    pub rbp: usize,
+    // the handler's own stack pointer, popped back into rsp so it must not change
+    isr_rsp: usize,
+    pub r8: usize,
+    pub r9: usize,
+    pub r10: usize,
+    pub r11: usize,
+    pub r12: usize,
+    pub r13: usize,
+    pub r14: usize,
+    pub r15: usize,
+}
+
+/// State of the interrupted code, saved on the stack by the cpu and isr.asm
+#[derive(Debug)]
+#[repr(C)]
+pub struct IsrData {
+    pub registers: Registers,
+    pub vector: usize,
+    /// Zero for vectors that don't push an error code
+    pub err_code: usize,
+    pub instruction: *const u8,
+    pub code_segment: usize,
+    pub flags: usize,
+    pub stack_pointer: *const usize,
+    pub stack_segment: usize,
+}
+
+impl IsrData {
+    /// Returns true if the interrupt arrived while running kernel code
+    pub fn from_kernel(&self) -> bool {
+        self.code_segment == KERNEL_CODE as usize
+    }
+}
+
+type Handler = Box<dyn Fn(&mut IsrData) -> bool + Send + Sync>;
+
+struct Entry {
+    id: usize,
+    handler: Handler,
+}
+
+/// Identifies a registered handler, see [unregister_handler]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: usize,
+}
+
+static HANDLERS: [RwLock<Vec<Entry>>; 256] = [const { RwLock::new(Vec::new()) }; 256];
+static NEXT_HANDLER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Innermost interrupt each cpu is handling, for panic reports
+static CURRENT_FRAME: CpuLocal<AtomicPtr<IsrData>> =
+    CpuLocal::new(|_| AtomicPtr::new(ptr::null_mut()));
+
+/// One bit per vector in [DYNAMIC_VECTORS], set if the vector is in use
+static ALLOCATED_VECTORS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+/// One bit per fixed vector the local apic delivers, see [set_lapic_vector]
+static LAPIC_VECTORS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+/// Adds a handler for `vector`, called after the ones registered before it
+///
+/// Data the handler needs can be moved into the closure. It runs with
+/// interrupts disabled and must not register or unregister handlers itself.
+pub fn register_handler(
+    vector: u8,
+    handler: impl Fn(&mut IsrData) -> bool + Send + Sync + 'static,
+) -> HandlerId {
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    let entry = Entry {
+        id,
+        handler: Box::new(handler),
+    };
+
+    // an interrupt on this cpu would deadlock on the write lock
+    without_interrupts(|| HANDLERS[vector as usize].write().push(entry));
+    HandlerId { vector, id }
+}
+
+/// Removes a handler, returns false if it was already removed
+pub fn unregister_handler(handler: HandlerId) -> bool {
+    let removed = without_interrupts(|| {
+        let mut handlers = HANDLERS[handler.vector as usize].write();
+        let index = handlers.iter().position(|entry| entry.id == handler.id)?;
+        Some(handlers.remove(index))
+    });
+    // dropped here, outside the lock, since it may own data that locks on drop
+    removed.is_some()
+}
+
+/// Reserves a vector from [DYNAMIC_VECTORS] for a device
+pub fn allocate_vector() -> Option<u8> {
+    DYNAMIC_VECTORS.find(|&vector| {
+        let (word, bit) = vector_bit(vector);
+        ALLOCATED_VECTORS[word].fetch_or(bit, Ordering::AcqRel) & bit == 0
+    })
+}
+
+/// Returns a vector from [allocate_vector], its handlers should be removed first
+pub fn free_vector(vector: u8) {
+    assert!(
+        DYNAMIC_VECTORS.contains(&vector),
+        "vector {vector} isn't dynamically allocated"
+    );
+    let (word, bit) = vector_bit(vector);
+    let prev = ALLOCATED_VECTORS[word].fetch_and(!bit, Ordering::AcqRel);
+    assert!(prev & bit != 0, "vector {vector} wasn't allocated");
+}
+
+/// Marks a fixed vector as delivered by the local apic, so handling it ends
+/// with an eoi
+///
+/// Vectors from [allocate_vector] are routed through the apics already, others
+/// like the pic's or `int` instructions must not be acknowledged.
+pub fn set_lapic_vector(vector: u8) {
+    let (word, bit) = vector_bit(vector);
+    LAPIC_VECTORS[word].fetch_or(bit, Ordering::Relaxed);
+}
+
+fn needs_eoi(vector: u8) -> bool {
+    let (word, bit) = vector_bit(vector);
+    let routed = ALLOCATED_VECTORS[word].load(Ordering::Relaxed)
+        | LAPIC_VECTORS[word].load(Ordering::Relaxed);
+    routed & bit != 0
+}
+
+fn vector_bit(vector: u8) -> (usize, u64) {
+    (vector as usize / 64, 1 << (vector % 64))
+}
+
+// not nomem, memory accesses must not move across the change
+pub fn enable() {
+    unsafe { asm!("sti", options(nostack)) }
+}
+
+pub fn disable() {
+    unsafe { asm!("cli", options(nostack)) }
+}
+
+pub fn are_enabled() -> bool {
+    RFlags::read().contains(RFlags::INTERRUPT)
+}
+
+/// Runs `f` with interrupts disabled, restoring the previous state afterwards
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let _guard = InterruptGuard::new();
+    f()
+}
+
+#[no_mangle]
+extern "C" fn isr_inner(data: &mut IsrData) {
+    let vector = data.vector;
+
+    // percpu data may not be ready yet this early
+    let frame = try_get_cpuid().map(|cpuid| CURRENT_FRAME.get(cpuid));
+    let outer = frame.map(|frame| frame.swap(data, Ordering::Relaxed));
+
+    let restore_frame = || {
+        if let (Some(frame), Some(outer)) = (frame, outer) {
+            frame.store(outer, Ordering::Relaxed);
+        }
+    };
+
+    if matches!(vector, 8 | 14) && data.from_kernel() {
+        check_stack_overflow(data);
+    }
+
+    let mut handled = false;
+    for entry in HANDLERS[vector].read().iter() {
+        handled |= (entry.handler)(data);
+    }
+
+    // exceptions caused by user code end it, instead of the kernel
+    if !handled && user::is_user(data) && matches!(vector, 0..32) && !matches!(vector, 2 | 18) {
+        restore_frame();
+        user::exit_on_exception(data);
+    }
+
+    if !handled {
+        default_handler(data);
+    }
+
+    if needs_eoi(vector as u8) {
+        lapic::eoi();
+    }
+
+    restore_frame();
+}
+
+/// Prints the registers saved in an interrupt frame
+pub fn print_frame(data: &IsrData) {
+    let regs = &data.registers;
+    let values = [
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("rbp", regs.rbp),
+        ("rsp", data.stack_pointer as usize),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+        ("rip", data.instruction as usize),
+        ("rfl", data.flags),
+        ("cs", data.code_segment),
+        ("ss", data.stack_segment),
+        ("err", data.err_code),
+    ];
+
+    for line in values.chunks(3) {
+        for (name, value) in line {
+            print!("\t{name:>3}: {value:#018x}");
+        }
+        println!();
+    }
+}
+
+/// Prints the frame of the interrupt the current cpu is handling, if any
+pub fn print_current_frame() {
+    let Some(cpuid) = try_get_cpuid() else {
+        return;
+    };
+    let frame = CURRENT_FRAME.get(cpuid).load(Ordering::Relaxed);
+    if let Some(data) = unsafe { frame.as_ref() } {
+        println!("interrupted by int {}:", data.vector);
+        print_frame(data);
+    }
+}
+
+fn default_handler(data: &IsrData) {
+    let rip = data.instruction as usize;
+    let vector = data.vector;
+    let err = data.err_code;
+
+    match vector {
+        3 => println!("int {vector}: {}\n\trip: {rip:#x}", EXCEPTIONS[vector]),
+        14 => page_fault(data),
+        8 | 10..=13 | 17 | 21 | 29 => panic!(
+            "int {vector} ({err}): {}\n\trip: {rip:#x}",
+            EXCEPTIONS[vector]
+        ),
+        0..32 => panic!("int {vector}: {}\n\trip: {rip:#x}", EXCEPTIONS[vector]),
+        32..256 => println!("unhandled int {vector}"),
+        256.. => unreachable!(),
+    }
+}
+
+bitflags! {
+    /// Error code pushed by a page fault
+    #[derive(Debug, Clone, Copy)]
+    pub struct PageFaultError: usize {
+        /// Caused by a protection violation, rather than a non present page
+        const PRESENT = 1 << 0;
+        const WRITE = 1 << 1;
+        const USER = 1 << 2;
+        /// A page table entry had a reserved bit set
+        const RESERVED_BIT = 1 << 3;
+        const INSTRUCTION_FETCH = 1 << 4;
+        const PROTECTION_KEY = 1 << 5;
+        const SHADOW_STACK = 1 << 6;
+        const SGX = 1 << 15;
+    }
+}
+
+/// Panics with a description of an unhandled page fault
+fn page_fault(data: &IsrData) -> ! {
+    let addr = Cr2::read();
+    let err = PageFaultError::from_bits_retain(data.err_code);
+
+    let access = if err.contains(PageFaultError::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if err.contains(PageFaultError::SHADOW_STACK) {
+        "shadow stack access"
+    } else if err.contains(PageFaultError::WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let mode = if err.contains(PageFaultError::USER) {
+        "user"
+    } else {
+        "kernel"
+    };
+    let cause = if err.contains(PageFaultError::RESERVED_BIT) {
+        "reserved bit set in a page table entry"
+    } else if err.contains(PageFaultError::PROTECTION_KEY) {
+        "protection key violation"
+    } else if err.contains(PageFaultError::PRESENT) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+
+    match try_get_cpuid() {
+        Some(cpuid) => println!("page fault on cpu {cpuid}"),
+        None => println!("page fault"),
+    }
+    println!("\t{mode} {access} of {addr:#x}: {cause}");
+    println!("\terror code: {err:?}");
+
+    // the fault may have happened while the mapper was locked
+    match KERNEL_MAPPER
+        .try_lock()
+        .map(|mut mapper| mapper.query(addr as *const ()))
+    {
+        None => println!("\tmapping: unknown, the mapper is locked"),
+        Some(None) => println!("\tmapping: unmapped"),
+        Some(Some(MappingKind::Gaurd)) => println!("\tmapping: guard page"),
+        Some(Some(kind)) => println!(
+            "\tmapping: {kind:?} ({}{}{})",
+            if kind.can_read() { 'r' } else { '-' },
+            if kind.can_write() { 'w' } else { '-' },
+            if kind.can_execute() { 'x' } else { '-' },
+        ),
+    }
+    print_frame(data);
+
+    panic!(
+        "page fault: {mode} {access} of {addr:#x} at rip {:#x}",
+        data.instruction as usize
+    );
+}
+
+/// Panics with a description of the stack if a fault hit a stack guard page
+///
+/// A real overflow faults again while pushing the page fault frame,
+/// so it arrives here as a double fault on its own stack
+fn check_stack_overflow(data: &IsrData) {
+    let addr = Cr2::read();
+
+    // the fault may have happened while the mapper was locked
+    let Some(kind) = KERNEL_MAPPER
+        .try_lock()
+        .and_then(|mut mapper| mapper.query(addr as *const ()))
+    else {
+        return;
+    };
+    if !matches!(kind, MappingKind::Gaurd) {
+        return;
+    }
+    let Some(info) = stack::find(addr).filter(|info| info.is_guard(addr)) else {
+        return;
+    };
+
+    let sp = data.stack_pointer as usize;
+    let rbp = data.registers.rbp;
+
+    match crate::arch::try_get_cpuid() {
+        Some(cpuid) => println!("kernel stack overflow on cpu {cpuid}"),
+        None => println!("kernel stack overflow"),
+    }
+    match info.cpu {
+        Some(owner) => println!("\tstack: {} of cpu {owner}", info.name),
+        None => println!("\tstack: {}", info.name),
+    }
+    println!(
+        "\tbounds: {:#x}..{:#x} ({} bytes)",
+        info.bottom,
+        info.top,
+        info.top - info.bottom
+    );
+    println!(
+        "\tfault address: {addr:#x}, stack pointer: {sp:#x}, depth: {} bytes",
+        info.top.saturating_sub(sp)
+    );
+    println!("\trip: {:#x}", data.instruction as usize);
+    println!("\tbacktrace:");
+    backtrace::print(rbp);
+
+    panic!("kernel stack overflow");
+}
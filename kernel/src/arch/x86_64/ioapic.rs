@@ -0,0 +1,198 @@
+//! I/O APIC driver, routes external interrupts to local apics
+
+use alloc::vec::Vec;
+use spin::Once;
+
+use crate::{
+    acpi::madt::{self, Polarity, Trigger},
+    boot,
+    cpuset::CpuSet,
+    mem::{mmio, PhysPtr},
+    println,
+    sync::IrqSpinlock,
+};
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+const DESTINATION_SHIFT: u64 = 56;
+
+struct IoApic {
+    regs: *mut u32,
+    gsi_base: u32,
+    entries: u32,
+}
+
+unsafe impl Send for IoApic {}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RouteError {
+    /// No io apic handles the gsi
+    NoIoApic(u32),
+    /// The cpu's apic id doesn't fit in a redirection entry
+    UnreachableCpu(u32),
+    /// None of the cpus in an affinity set can be reached
+    NoReachableCpu,
+}
+
+static IO_APICS: Once<Vec<IrqSpinlock<IoApic>>> = Once::new();
+
+impl IoApic {
+    fn read(&mut self, reg: u32) -> u32 {
+        unsafe {
+            self.regs.byte_add(IOREGSEL).write_volatile(reg);
+            self.regs.byte_add(IOWIN).read_volatile()
+        }
+    }
+
+    fn write(&mut self, reg: u32, val: u32) {
+        unsafe {
+            self.regs.byte_add(IOREGSEL).write_volatile(reg);
+            self.regs.byte_add(IOWIN).write_volatile(val);
+        }
+    }
+
+    fn read_entry(&mut self, index: u32) -> u64 {
+        let low = self.read(REDIRECTION_TABLE + index * 2) as u64;
+        let high = self.read(REDIRECTION_TABLE + index * 2 + 1) as u64;
+        (high << 32) | low
+    }
+
+    fn write_entry(&mut self, index: u32, entry: u64) {
+        // mask first so the entry is never live while half written
+        self.write(REDIRECTION_TABLE + index * 2, MASKED as u32);
+        self.write(REDIRECTION_TABLE + index * 2 + 1, (entry >> 32) as u32);
+        self.write(REDIRECTION_TABLE + index * 2, entry as u32);
+    }
+}
+
+/// Masks every io apic input, called once on the bsp
+pub fn init() {
+    crate::assert_once!();
+
+    IO_APICS.call_once(|| {
+        let Some(madt) = madt::get() else {
+            return Vec::new();
+        };
+
+        madt.io_apics
+            .iter()
+            .map(|info| {
+                let regs = mmio::map_mmio(PhysPtr::new(info.address as usize), IOWIN + 4)
+                    .expect("critical mapping failed");
+                let mut io_apic = IoApic {
+                    regs,
+                    gsi_base: info.gsi_base,
+                    entries: 0,
+                };
+                io_apic.entries = ((io_apic.read(VERSION) >> 16) & 0xff) + 1;
+
+                for index in 0..io_apic.entries {
+                    io_apic.write_entry(index, MASKED);
+                }
+
+                println!(
+                    "ioapic {}: gsi {}..{} at {:#x}",
+                    info.id,
+                    io_apic.gsi_base,
+                    io_apic.gsi_base + io_apic.entries,
+                    info.address
+                );
+                IrqSpinlock::new(io_apic)
+            })
+            .collect()
+    });
+}
+
+/// Runs `f` with the io apic handling `gsi` and the gsi's index in it
+fn with_gsi<T>(gsi: u32, f: impl FnOnce(&mut IoApic, u32) -> T) -> Result<T, RouteError> {
+    IO_APICS
+        .get()
+        .expect("ioapic isn't initialized")
+        .iter()
+        .find_map(|io_apic| {
+            let mut io_apic = io_apic.lock();
+            let index = gsi.checked_sub(io_apic.gsi_base)?;
+            (index < io_apic.entries).then(|| f(&mut io_apic, index))
+        })
+        .ok_or(RouteError::NoIoApic(gsi))
+}
+
+/// Delivers `gsi` to `vector` on the cpu with the given cpuid, and unmasks it
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    cpuid: u32,
+    polarity: Polarity,
+    trigger: Trigger,
+) -> Result<(), RouteError> {
+    let apic_id = destination(cpuid)?;
+    let mut entry = vector as u64 | ((apic_id as u64) << DESTINATION_SHIFT);
+    if polarity == Polarity::ActiveLow {
+        entry |= ACTIVE_LOW;
+    }
+    if trigger == Trigger::Level {
+        entry |= LEVEL_TRIGGERED;
+    }
+
+    with_gsi(gsi, |io_apic, index| io_apic.write_entry(index, entry))
+}
+
+/// Routes a legacy isa irq, following the madt's overrides
+///
+/// Returns the gsi the irq is connected to
+pub fn route_isa_irq(irq: u8, vector: u8, cpuid: u32) -> Result<u32, RouteError> {
+    let (gsi, polarity, trigger) = match madt::get() {
+        Some(madt) => madt.isa_irq(irq),
+        None => (irq as u32, Polarity::ActiveHigh, Trigger::Edge),
+    };
+    route_gsi(gsi, vector, cpuid, polarity, trigger)?;
+    Ok(gsi)
+}
+
+/// Moves `gsi` to the first cpu in `cpus` it can be delivered to, keeping its
+/// vector and mask
+///
+/// Returns the cpuid it was moved to
+pub fn set_affinity(gsi: u32, cpus: &CpuSet) -> Result<u32, RouteError> {
+    let (cpuid, apic_id) = cpus
+        .iter()
+        .find_map(|cpuid| Some((cpuid, destination(cpuid).ok()?)))
+        .ok_or(RouteError::NoReachableCpu)?;
+
+    with_gsi(gsi, |io_apic, index| {
+        let entry = io_apic.read_entry(index) & !(0xff << DESTINATION_SHIFT);
+        io_apic.write_entry(index, entry | ((apic_id as u64) << DESTINATION_SHIFT));
+    })?;
+    Ok(cpuid)
+}
+
+/// Returns the apic id to put in a redirection entry for a cpu
+fn destination(cpuid: u32) -> Result<u32, RouteError> {
+    // physical destination mode only has room for 8 bit ids
+    let apic_id = boot::apic_id(cpuid);
+    if apic_id > 0xff {
+        return Err(RouteError::UnreachableCpu(apic_id));
+    }
+    Ok(apic_id)
+}
+
+pub fn mask(gsi: u32) -> Result<(), RouteError> {
+    with_gsi(gsi, |io_apic, index| {
+        let entry = io_apic.read_entry(index);
+        io_apic.write_entry(index, entry | MASKED);
+    })
+}
+
+pub fn unmask(gsi: u32) -> Result<(), RouteError> {
+    with_gsi(gsi, |io_apic, index| {
+        let entry = io_apic.read_entry(index);
+        io_apic.write_entry(index, entry & !MASKED);
+    })
+}
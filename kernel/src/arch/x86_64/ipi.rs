@@ -0,0 +1,189 @@
+//! Inter processor interrupts and cross cpu function calls
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use alloc::{boxed::Box, collections::vec_deque::VecDeque, sync::Arc};
+
+use crate::{boot, cpulocal::CpuLocal, cpuset::CpuSet, mem::tlb, sync::IrqSpinlock};
+
+use super::{
+    get_cpuid, interrupts,
+    lapic::{self, Delivery, Destination},
+};
+
+/// Tells a cpu to run the calls queued for it
+pub const CALL_VECTOR: u8 = 0xf1;
+/// Tells a cpu to flush its tlb, see [tlb](crate::mem::tlb)
+pub const TLB_VECTOR: u8 = 0xf2;
+const NMI_VECTOR: u8 = 2;
+
+/// How long [stop_all_but_self] waits for the other cpus to halt
+const STOP_TIMEOUT_SPINS: usize = 100_000_000;
+
+struct Call {
+    f: Box<dyn Fn() + Send + Sync>,
+    /// Number of cpus that haven't finished running `f`
+    pending: AtomicU32,
+}
+
+/// Set once the handlers are registered, other cpus can't be asked anything
+/// before that
+static READY: AtomicBool = AtomicBool::new(false);
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static STOPPED: AtomicU32 = AtomicU32::new(0);
+
+static QUEUES: CpuLocal<IrqSpinlock<VecDeque<Arc<Call>>>> =
+    CpuLocal::new(|_| IrqSpinlock::new(VecDeque::new()));
+
+/// Registers the cross call handler, called once on the bsp
+pub fn init() {
+    crate::assert_once!();
+
+    interrupts::set_lapic_vector(CALL_VECTOR);
+    interrupts::register_handler(CALL_VECTOR, |_| {
+        run_queued();
+        true
+    });
+    interrupts::set_lapic_vector(TLB_VECTOR);
+    interrupts::register_handler(TLB_VECTOR, |_| {
+        tlb::flush();
+        true
+    });
+    interrupts::register_handler(NMI_VECTOR, |_| {
+        if !STOPPING.load(Ordering::Acquire) {
+            return false;
+        }
+        STOPPED.fetch_add(1, Ordering::AcqRel);
+        // nmis stay blocked until iretq, so nothing can wake us
+        super::hcf();
+    });
+    READY.store(true, Ordering::Release);
+}
+
+/// Halts every other cpu with an nmi, waiting a short while for them to stop
+///
+/// Used when panicking, so this never takes any locks
+pub fn stop_all_but_self() {
+    if STOPPING.swap(true, Ordering::AcqRel) || !lapic::is_initialized() {
+        return;
+    }
+
+    send_nmi_all_but_self();
+
+    let others = boot::cpu_count() - 1;
+    for _ in 0..STOP_TIMEOUT_SPINS {
+        if STOPPED.load(Ordering::Acquire) >= others {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Sends `vector` to the cpu with the given cpuid
+pub fn send(cpuid: u32, vector: u8) {
+    lapic::send_ipi(
+        Destination::ApicId(boot::apic_id(cpuid)),
+        Delivery::Fixed(vector),
+    );
+}
+
+/// Sends `vector` to every cpu except the current one
+pub fn send_all_but_self(vector: u8) {
+    lapic::send_ipi(Destination::AllButSelf, Delivery::Fixed(vector));
+}
+
+/// Sends `vector` to every cpu in `cpus`
+pub fn send_set(cpus: &CpuSet, vector: u8) {
+    for cpuid in cpus {
+        send(cpuid, vector);
+    }
+}
+
+/// Asks every other cpu to flush its tlb, without waiting for them
+pub fn request_tlb_flush() {
+    if READY.load(Ordering::Acquire) {
+        send_all_but_self(TLB_VECTOR);
+    }
+}
+
+pub fn send_nmi(cpuid: u32) {
+    lapic::send_ipi(Destination::ApicId(boot::apic_id(cpuid)), Delivery::Nmi);
+}
+
+pub fn send_nmi_all_but_self() {
+    lapic::send_ipi(Destination::AllButSelf, Delivery::Nmi);
+}
+
+/// Runs `f` on the given cpu and waits for it to finish
+///
+/// The target cpu must have interrupts enabled, or this never returns.
+pub fn run_on(cpuid: u32, f: impl Fn() + Send + Sync + 'static) {
+    if cpuid == get_cpuid() {
+        f();
+        return;
+    }
+
+    let call = queue(Box::new(f), &CpuSet::only(cpuid));
+    send(cpuid, CALL_VECTOR);
+    wait(&call);
+}
+
+/// Runs `f` on every cpu in `cpus`, and waits for all of them to finish
+///
+/// The target cpus must have interrupts enabled, or this never returns.
+pub fn run_on_set(cpus: &CpuSet, f: impl Fn() + Send + Sync + 'static) {
+    let current = get_cpuid();
+    let others = cpus.clone();
+    let on_current = current < others.capacity() && others.remove(current);
+
+    let call = queue(Box::new(f), &others);
+    match others.len() {
+        0 => {}
+        // one ipi reaches all of them
+        n if n == boot::cpu_count() - 1 => send_all_but_self(CALL_VECTOR),
+        _ => send_set(&others, CALL_VECTOR),
+    }
+    if on_current {
+        (call.f)();
+    }
+    wait(&call);
+}
+
+/// Runs `f` on every cpu, including the current one, and waits for all of them
+/// to finish
+pub fn run_on_all(f: impl Fn() + Send + Sync + 'static) {
+    run_on_set(&CpuSet::all(), f);
+}
+
+fn queue(f: Box<dyn Fn() + Send + Sync>, cpus: &CpuSet) -> Arc<Call> {
+    let call = Arc::new(Call {
+        f,
+        pending: AtomicU32::new(cpus.len()),
+    });
+    for cpuid in cpus {
+        QUEUES.get(cpuid).lock().push_back(call.clone());
+    }
+    call
+}
+
+fn wait(call: &Call) {
+    while call.pending.load(Ordering::Acquire) != 0 {
+        // another cpu may be waiting on us in turn, with interrupts disabled
+        run_queued();
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs every call queued for the current cpu
+fn run_queued() {
+    let queue = QUEUES.local();
+    loop {
+        // the lock can't be held while calling, or nested calls would deadlock
+        let next = queue.lock().pop_front();
+        let Some(call) = next else {
+            break;
+        };
+        (call.f)();
+        call.pending.fetch_sub(1, Ordering::Release);
+    }
+}
@@ -0,0 +1,309 @@
+//! Local APIC driver
+//!
+//! Uses x2APIC mode when the cpu supports it, and the memory mapped xAPIC
+//! registers otherwise. The mode is chosen once by the first cpu so every cpu
+//! agrees on it.
+
+use core::{
+    sync::atomic::{fence, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+
+use crate::{
+    assert_once_percpu,
+    cpulocal::CpuLocal,
+    mem::{mmio, PhysPtr, PAGE_SIZE},
+    println,
+};
+
+use super::{
+    cpuid::{self, CpuFeatures},
+    interrupts, pit,
+    registers::model_specific::{read_msr, write_msr, ApicBase, ApicBaseFlags, TscDeadline},
+    tsc,
+};
+
+pub const TIMER_VECTOR: u8 = 0xf0;
+pub const ERROR_VECTOR: u8 = 0xfe;
+/// Never needs an eoi
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const ID: u32 = 0x20;
+const TASK_PRIORITY: u32 = 0x80;
+const EOI: u32 = 0xb0;
+const SPURIOUS: u32 = 0xf0;
+const ERROR_STATUS: u32 = 0x280;
+const ICR_LOW: u32 = 0x300;
+const ICR_HIGH: u32 = 0x310;
+const LVT_TIMER: u32 = 0x320;
+const LVT_LINT0: u32 = 0x350;
+const LVT_ERROR: u32 = 0x370;
+const TIMER_INITIAL: u32 = 0x380;
+const TIMER_CURRENT: u32 = 0x390;
+const TIMER_DIVIDE: u32 = 0x3e0;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 0b01 << 17;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+/// Divide the timer clock by 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// How long the timer is measured against the pit
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+enum Mode {
+    XApic(*mut u32),
+    X2Apic,
+}
+
+unsafe impl Send for Mode {}
+unsafe impl Sync for Mode {}
+
+struct Calibration {
+    /// Timer ticks per millisecond with [TIMER_DIVIDE_16]
+    timer_per_ms: u64,
+}
+
+static MODE: Once<Mode> = Once::new();
+static CALIBRATION: Once<Calibration> = Once::new();
+static TICKS: CpuLocal<AtomicU64> = CpuLocal::new(|_| AtomicU64::new(0));
+
+fn has_x2apic() -> bool {
+    cpuid::has(CpuFeatures::X2APIC)
+}
+
+/// Returns true if [set_deadline] can be used
+pub fn has_tsc_deadline() -> bool {
+    cpuid::has(CpuFeatures::TSC_DEADLINE)
+}
+
+fn mode() -> &'static Mode {
+    MODE.get().expect("lapic isn't initialized")
+}
+
+fn read(reg: u32) -> u32 {
+    match mode() {
+        Mode::XApic(base) => unsafe { base.byte_add(reg as usize).read_volatile() },
+        Mode::X2Apic => unsafe { read_msr(X2APIC_MSR_BASE + reg / 16) as u32 },
+    }
+}
+
+fn write(reg: u32, val: u32) {
+    match mode() {
+        Mode::XApic(base) => unsafe { base.byte_add(reg as usize).write_volatile(val) },
+        Mode::X2Apic => unsafe { write_msr(X2APIC_MSR_BASE + reg / 16, val as u64) },
+    }
+}
+
+/// Enables this cpu's local apic, with every interrupt source masked
+pub fn init(cpuid: u32) {
+    assert_once_percpu!(cpuid);
+
+    let mode = MODE.call_once(|| {
+        if has_x2apic() {
+            println!("lapic: using x2apic");
+            Mode::X2Apic
+        } else {
+            let (base, _) = ApicBase::read();
+            let regs = mmio::map_mmio(PhysPtr::<u32>::new(base.addr()), PAGE_SIZE)
+                .expect("critical mapping failed");
+            println!("lapic: using xapic at {:#x}", base.addr());
+            Mode::XApic(regs)
+        }
+    });
+
+    // going from disabled straight to x2apic mode faults, so enable first
+    unsafe { ApicBase::update(|flags| flags.insert(ApicBaseFlags::ENABLE)) };
+    if matches!(mode, Mode::X2Apic) {
+        unsafe { ApicBase::update(|flags| flags.insert(ApicBaseFlags::X2APIC_ENABLE)) };
+    }
+
+    write(TASK_PRIORITY, 0);
+    write(LVT_TIMER, LVT_MASKED);
+    write(LVT_LINT0, LVT_MASKED);
+    write(LVT_ERROR, ERROR_VECTOR as u32);
+    clear_errors();
+    write(SPURIOUS, SPURIOUS_VECTOR as u32 | SPURIOUS_ENABLE);
+
+    CALIBRATION.call_once(calibrate);
+
+    if cpuid == 0 {
+        interrupts::register_handler(SPURIOUS_VECTOR, |_| true);
+        interrupts::set_lapic_vector(ERROR_VECTOR);
+        interrupts::set_lapic_vector(TIMER_VECTOR);
+        interrupts::register_handler(ERROR_VECTOR, |_| {
+            println!("lapic error: {:#x}", clear_errors());
+            true
+        });
+        interrupts::register_handler(TIMER_VECTOR, |_| {
+            // handlers run with interrupts disabled
+            unsafe { TICKS.force() }.fetch_add(1, Ordering::Relaxed);
+            true
+        });
+    }
+}
+
+/// Returns the bits that were set in the error status register
+fn clear_errors() -> u32 {
+    // the register only updates on a write
+    write(ERROR_STATUS, 0);
+    let errors = read(ERROR_STATUS);
+    write(ERROR_STATUS, 0);
+    errors
+}
+
+/// Measures the timer frequency with the pit
+fn calibrate() -> Calibration {
+    write(TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(LVT_TIMER, LVT_MASKED);
+
+    write(TIMER_INITIAL, u32::MAX);
+    pit::wait(CALIBRATION_TIME);
+    let remaining = read(TIMER_CURRENT);
+    write(TIMER_INITIAL, 0);
+
+    let ms = CALIBRATION_TIME.as_millis() as u64;
+    let calibration = Calibration {
+        timer_per_ms: (u32::MAX - remaining) as u64 / ms,
+    };
+    println!("lapic: timer runs at {} kHz", calibration.timer_per_ms * 16);
+    calibration
+}
+
+fn calibration() -> &'static Calibration {
+    CALIBRATION.get().expect("lapic isn't initialized")
+}
+
+pub fn is_initialized() -> bool {
+    MODE.is_completed()
+}
+
+/// Signals the end of an interrupt, must not be sent for [SPURIOUS_VECTOR]
+pub fn eoi() {
+    // interrupts can't arrive before the first cpu initializes
+    if is_initialized() {
+        write(EOI, 0);
+    }
+}
+
+/// Returns the apic id of the current cpu
+pub fn id() -> u32 {
+    match mode() {
+        Mode::XApic(_) => read(ID) >> 24,
+        Mode::X2Apic => read(ID),
+    }
+}
+
+/// Who an ipi is sent to
+#[derive(Debug, Clone, Copy)]
+pub enum Destination {
+    ApicId(u32),
+    AllButSelf,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Delivery {
+    Fixed(u8),
+    Nmi,
+}
+
+/// Sends an inter processor interrupt and waits until the apic accepts it
+pub fn send_ipi(destination: Destination, delivery: Delivery) {
+    let mut low = ICR_ASSERT;
+    low |= match delivery {
+        Delivery::Fixed(vector) => vector as u32,
+        Delivery::Nmi => 0b100 << 8,
+    };
+    let apic_id = match destination {
+        Destination::ApicId(apic_id) => apic_id,
+        Destination::AllButSelf => {
+            low |= 0b11 << 18;
+            0
+        }
+    };
+
+    match mode() {
+        // an ipi sent from an interrupt handler in between would change the
+        // destination
+        Mode::XApic(_) => interrupts::without_interrupts(|| {
+            write(ICR_HIGH, apic_id << 24);
+            write(ICR_LOW, low);
+            while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }),
+        // a single 64 bit register, without a pending bit
+        Mode::X2Apic => unsafe {
+            write_msr(
+                X2APIC_MSR_BASE + ICR_LOW / 16,
+                ((apic_id as u64) << 32) | low as u64,
+            )
+        },
+    }
+}
+
+/// Converts a duration into timer ticks, at least one
+fn timer_ticks(duration: Duration) -> u32 {
+    let ticks = duration.as_nanos() * calibration().timer_per_ms as u128 / 1_000_000;
+    ticks.clamp(1, u32::MAX as u128) as u32
+}
+
+/// Fires [TIMER_VECTOR] on this cpu every `period`
+pub fn set_periodic(period: Duration) {
+    write(LVT_TIMER, LVT_MASKED);
+    write(TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(LVT_TIMER, TIMER_VECTOR as u32 | TIMER_PERIODIC);
+    write(TIMER_INITIAL, timer_ticks(period));
+}
+
+/// Fires [TIMER_VECTOR] on this cpu once after `delay`
+pub fn set_oneshot(delay: Duration) {
+    write(LVT_TIMER, LVT_MASKED);
+    write(TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(LVT_TIMER, TIMER_VECTOR as u32);
+    write(TIMER_INITIAL, timer_ticks(delay));
+}
+
+/// Fires [TIMER_VECTOR] on this cpu once the tsc reaches `deadline`
+///
+/// Panics if the cpu doesn't support tsc deadline mode, see [has_tsc_deadline]
+pub fn set_deadline(deadline: u64) {
+    assert!(has_tsc_deadline(), "tsc deadline mode isn't supported");
+
+    write(LVT_TIMER, TIMER_VECTOR as u32 | TIMER_TSC_DEADLINE);
+    // the lvt write has to land before the msr write, or the deadline may be ignored
+    fence(Ordering::SeqCst);
+    unsafe { TscDeadline::write_raw(deadline.max(1)) };
+}
+
+/// Fires [TIMER_VECTOR] on this cpu after `delay`, using tsc deadline mode
+/// when available
+pub fn set_timeout(delay: Duration) {
+    if has_tsc_deadline() {
+        let cycles = tsc::nanos_to_cycles(delay.as_nanos().try_into().unwrap_or(u64::MAX));
+        set_deadline(tsc::read().saturating_add(cycles));
+    } else {
+        set_oneshot(delay);
+    }
+}
+
+/// Stops this cpu's timer
+pub fn stop_timer() {
+    write(LVT_TIMER, LVT_MASKED);
+    write(TIMER_INITIAL, 0);
+    if has_tsc_deadline() {
+        unsafe { TscDeadline::write_raw(0) };
+    }
+}
+
+/// Returns the number of timer interrupts this cpu has received
+pub fn ticks() -> u64 {
+    TICKS.local().load(Ordering::Relaxed)
+}
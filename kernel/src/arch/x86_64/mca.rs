@@ -0,0 +1,269 @@
+//! Machine check architecture
+//!
+//! Hardware errors are logged in per cpu banks. Uncorrected ones raise a
+//! machine check exception, corrected ones only get logged, so the banks are
+//! polled for them from the timer interrupt.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{assert_once_percpu, cpulocal::CpuLocal, print, println, time};
+
+use super::{
+    cpuid::{self, CpuFeatures},
+    interrupts::{self, IsrData},
+    lapic,
+    registers::{
+        control::{Cr4, Cr4Flags},
+        model_specific::{read_msr, write_msr, McgCap, McgCtl, McgStatus},
+    },
+    try_get_cpuid,
+};
+
+const MACHINE_CHECK: u8 = 18;
+
+/// How often the banks are checked for corrected errors
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+const CAP_COUNT_MASK: u64 = 0xff;
+const CAP_CTL_PRESENT: u64 = 1 << 8;
+
+/// The interrupted instruction can be restarted
+const STATUS_RIPV: u64 = 1 << 0;
+/// A machine check is in progress, another one would shut down the cpu
+const STATUS_MCIP: u64 = 1 << 2;
+
+const BANK_CTL: u32 = 0x400;
+const BANK_STATUS: u32 = 0x401;
+const BANK_ADDR: u32 = 0x402;
+const BANK_MISC: u32 = 0x403;
+const BANK_STRIDE: u32 = 4;
+
+const VALID: u64 = 1 << 63;
+/// An earlier error was overwritten
+const OVERFLOW: u64 = 1 << 62;
+const UNCORRECTED: u64 = 1 << 61;
+const MISC_VALID: u64 = 1 << 59;
+const ADDR_VALID: u64 = 1 << 58;
+/// Processor context corrupt, execution can't continue
+const CONTEXT_CORRUPT: u64 = 1 << 57;
+/// Software has to act before execution continues
+const ACTION_REQUIRED: u64 = 1 << 55;
+
+/// Next time this cpu polls for corrected errors, in nanoseconds since boot
+static NEXT_POLL: CpuLocal<AtomicU64> = CpuLocal::new(|_| AtomicU64::new(0));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Fixed by the hardware
+    Corrected,
+    /// Data was lost, but the cpu can keep running
+    Uncorrected,
+    Fatal,
+}
+
+/// An error logged in one bank
+#[derive(Debug, Clone, Copy)]
+pub struct BankError {
+    pub bank: u32,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl BankError {
+    pub fn severity(&self) -> Severity {
+        if self.status & UNCORRECTED == 0 {
+            Severity::Corrected
+        } else if self.status & (CONTEXT_CORRUPT | ACTION_REQUIRED) != 0 {
+            Severity::Fatal
+        } else {
+            Severity::Uncorrected
+        }
+    }
+
+    /// The architectural error code
+    pub fn code(&self) -> u16 {
+        self.status as u16
+    }
+
+    /// The model specific error code
+    pub fn model_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bank {}: {:?} {} error, code {:#06x}/{:#06x}",
+            self.bank,
+            self.severity(),
+            describe(self.code()),
+            self.code(),
+            self.model_code()
+        )?;
+        if let Some(addr) = self.addr {
+            write!(f, ", addr {addr:#x}")?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {misc:#x}")?;
+        }
+        if self.status & OVERFLOW != 0 {
+            write!(f, ", earlier errors lost")?;
+        }
+        Ok(())
+    }
+}
+
+/// Names the class of an architectural error code
+fn describe(code: u16) -> &'static str {
+    // bit 12 only says whether corrected errors are being filtered
+    let code = code & !(1 << 12);
+    match code {
+        0x0000 => "no",
+        0x0001 => "unclassified",
+        0x0002 => "microcode rom parity",
+        0x0003 => "external",
+        0x0004 => "frc",
+        0x0005 => "internal parity",
+        0x0006 => "smm handler code access",
+        0x0400 => "internal timer",
+        0x0401..=0x07ff => "internal unclassified",
+        _ if code & 0xeffc == 0x000c => "cache hierarchy",
+        _ if code & 0xeff0 == 0x0010 => "tlb",
+        _ if code & 0xef80 == 0x0080 => "memory controller",
+        _ if code & 0xef00 == 0x0100 => "cache",
+        _ if code & 0xe800 == 0x0800 => "bus",
+        _ => "unknown",
+    }
+}
+
+fn bank_msr(reg: u32, bank: u32) -> u32 {
+    reg + bank * BANK_STRIDE
+}
+
+fn bank_count() -> u32 {
+    if !cpuid::has(CpuFeatures::MCA) {
+        return 0;
+    }
+    (unsafe { McgCap::read_raw() } & CAP_COUNT_MASK) as u32
+}
+
+/// Reads and clears every bank whose error `filter` accepts
+fn drain(filter: impl Fn(u64) -> bool, mut f: impl FnMut(BankError)) {
+    for bank in 0..bank_count() {
+        let status = unsafe { read_msr(bank_msr(BANK_STATUS, bank)) };
+        if status & VALID == 0 || !filter(status) {
+            continue;
+        }
+
+        let read_if =
+            |flag, reg| (status & flag != 0).then(|| unsafe { read_msr(bank_msr(reg, bank)) });
+        let error = BankError {
+            bank,
+            status,
+            addr: read_if(ADDR_VALID, BANK_ADDR),
+            misc: read_if(MISC_VALID, BANK_MISC),
+        };
+        unsafe { write_msr(bank_msr(BANK_STATUS, bank), 0) };
+        f(error);
+    }
+}
+
+/// Enables every bank and machine check exceptions, called once per cpu
+pub fn init(cpuid: u32) {
+    assert_once_percpu!(cpuid);
+
+    let features = cpuid::info_of(cpuid).features;
+    if !features.contains(CpuFeatures::MCE) {
+        println!("mca: cpu {cpuid} has no machine check exception");
+        return;
+    }
+
+    if features.contains(CpuFeatures::MCA) {
+        let cap = unsafe { McgCap::read_raw() };
+        if cap & CAP_CTL_PRESENT != 0 {
+            unsafe { McgCtl::write_raw(u64::MAX) };
+        }
+
+        let banks = (cap & CAP_COUNT_MASK) as u32;
+        for bank in 0..banks {
+            // errors from before a warm reset survive it
+            let status = unsafe { read_msr(bank_msr(BANK_STATUS, bank)) };
+            if status & VALID != 0 {
+                println!("mca: cpu {cpuid} bank {bank} logged {status:#018x} before boot");
+            }
+            unsafe {
+                write_msr(bank_msr(BANK_CTL, bank), u64::MAX);
+                write_msr(bank_msr(BANK_STATUS, bank), 0);
+            }
+        }
+        if cpuid == 0 {
+            println!("mca: {banks} banks");
+        }
+    }
+
+    if cpuid == 0 {
+        interrupts::register_handler(MACHINE_CHECK, machine_check);
+        interrupts::register_handler(lapic::TIMER_VECTOR, |_| {
+            poll_on_timer();
+            false
+        });
+    }
+
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+}
+
+/// Logs and clears every bank, panics if execution can't continue
+///
+/// Runs on its own stack, and can interrupt anything, including code holding
+/// the console lock, so it never waits for it.
+fn machine_check(_data: &mut IsrData) -> bool {
+    let status = unsafe { McgStatus::read_raw() };
+    let mut fatal = status & STATUS_RIPV == 0;
+
+    drain(
+        |_| true,
+        |error| {
+            fatal |= error.severity() == Severity::Fatal;
+            print::print_nonblocking(format_args!("mca: {error}\n"));
+        },
+    );
+
+    if fatal {
+        panic!("fatal machine check, status {status:#x}");
+    }
+    unsafe { McgStatus::write_raw(status & !STATUS_MCIP) };
+    true
+}
+
+/// Logs and clears the corrected errors on this cpu
+///
+/// Uncorrected errors are left for the machine check handler.
+pub fn poll() {
+    drain(
+        |status| status & UNCORRECTED == 0,
+        |error| match try_get_cpuid() {
+            Some(cpuid) => println!("mca: cpu {cpuid} {error}"),
+            None => println!("mca: {error}"),
+        },
+    );
+}
+
+fn poll_on_timer() {
+    let Some(cpuid) = try_get_cpuid() else {
+        return;
+    };
+    let now = time::monotonic();
+    let next = NEXT_POLL.get(cpuid);
+    if now < next.load(Ordering::Relaxed) {
+        return;
+    }
+    next.store(now + POLL_INTERVAL.as_nanos() as u64, Ordering::Relaxed);
+    poll();
+}
@@ -3,17 +3,36 @@ compile_error!("this code only works on x86_64");
 
 use core::arch::asm;
 
-use registers::control::Cr3;
+use registers::control::{Cr0, Cr2, Cr3, Cr4};
 
 use crate::{
-    assert_once_percpu, boot,
-    mem::{Mapper, KERNEL_MAPPER},
+    acpi, assert_once_percpu, boot,
+    cpuset::CpuSet,
+    mem::{tlb, Mapper, KERNEL_MAPPER},
     println,
+    time::DateTime,
 };
 
+pub mod cpuid;
+pub mod fpu;
+pub mod hpet;
+pub mod interrupts;
+pub mod ioapic;
+pub mod ipi;
+pub mod lapic;
+pub mod mca;
 mod percpu;
+mod pic;
+mod pit;
+mod port;
 mod registers;
+pub mod rtc;
 mod structures;
+pub mod syscall;
+pub mod tsc;
+pub mod user;
+
+pub use percpu::RESERVED as PERCPU_RESERVED;
 
 /// # Safety
 /// This function must be called exactly once per core
@@ -21,18 +40,113 @@ mod structures;
 pub fn init(cpuid: u32) {
     let cpus = boot::cpu_count();
     assert!(cpuid < cpus);
+
+    // the heap is only mapped in the kernel's page tables
+    unsafe { Cr3::write(KERNEL_MAPPER.lock().ptroot(), 0) };
     assert_once_percpu!(cpuid);
+    unsafe { percpu::init(cpuid) };
+    tlb::flush();
 
-    unsafe { Cr3::write_raw(KERNEL_MAPPER.lock().ptroot().addr() as u64) };
+    cpuid::init(cpuid);
+
+    if cpuid == 0 {
+        // the hpet is a calibration reference for the tsc
+        acpi::init();
+        hpet::init();
+    }
+    tsc::init(cpuid);
 
     println!("initilizing gdt/tss...");
-    structures::init();
+    structures::init(cpuid);
 
-    unsafe { percpu::init(cpuid) };
+    println!("initilizing lapic...");
+    lapic::init(cpuid);
+
+    syscall::init(cpuid);
+    fpu::init(cpuid);
+    mca::init(cpuid);
+
+    if cpuid == 0 {
+        println!("initilizing pic/ioapic...");
+        pic::disable();
+        ioapic::init();
+        ipi::init();
+        rtc::init();
+    }
 }
 
+/// Returns the current cpu's id, which it must have set up percpu data for
+#[inline(always)]
 pub fn get_cpuid() -> u32 {
-    percpu::get_percpu().cpuid
+    percpu::cpuid()
+}
+
+/// Like [get_cpuid], but returns `None` until this cpu has set up its percpu
+/// data
+pub fn try_get_cpuid() -> Option<u32> {
+    percpu::is_ready().then(percpu::cpuid)
+}
+
+/// Returns the start of the current cpu's [cpulocal](crate::cpulocal) area
+#[inline(always)]
+pub fn this_cpu_area() -> *mut u8 {
+    percpu::this_cpu_area()
+}
+
+/// Nanoseconds since boot, zero until the clock is calibrated
+pub fn nanos_since_boot() -> u64 {
+    tsc::nanos_since_boot()
+}
+
+pub fn interrupts_enabled() -> bool {
+    interrupts::are_enabled()
+}
+
+pub fn enable_interrupts() {
+    interrupts::enable();
+}
+
+pub fn disable_interrupts() {
+    interrupts::disable();
+}
+
+/// Reads the battery backed clock, in UTC
+pub fn read_wall_clock() -> Option<DateTime> {
+    rtc::read()
+}
+
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) }
+    rbp
+}
+
+/// Runs `f` on every cpu in `cpus`, and waits for all of them to finish
+pub fn run_on_set(cpus: &CpuSet, f: impl Fn() + Send + Sync + 'static) {
+    ipi::run_on_set(cpus, f);
+}
+
+/// Asks every other cpu to flush its tlb, see [tlb]
+pub fn request_tlb_flush() {
+    ipi::request_tlb_flush();
+}
+
+/// Halts every other cpu, used when panicking
+pub fn stop_other_cpus() {
+    ipi::stop_all_but_self();
+}
+
+/// Prints the state of the current cpu, used when panicking
+pub fn print_registers() {
+    interrupts::print_current_frame();
+    println!(
+        "\tcr0: {:#018x}\tcr2: {:#018x}\tcr3: {:#018x}\tcr4: {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read_raw(),
+        Cr4::read_raw()
+    );
 }
 
 pub fn debug_print(s: &str) {
@@ -47,8 +161,9 @@ pub fn debug_print(s: &str) {
 }
 
 pub fn hcf() -> ! {
+    unsafe { asm!("cli") };
+    tlb::park();
     unsafe {
-        asm!("cli");
         loop {
             asm!("hlt");
         }
@@ -1,61 +1,82 @@
-use core::{
-    ptr,
-    sync::atomic::{AtomicU32, Ordering},
-};
+//! The start of every cpu's [cpulocal](crate::cpulocal) area, reached through gs
+//!
+//! The gs base of each cpu points at its area, so the fields here are a single
+//! gs relative load away, and so is the area itself through `selfptr`.
 
-use alloc::boxed::Box;
+use core::{arch::asm, mem::offset_of};
 
-use crate::boot;
+use crate::cpulocal;
 
 use super::registers::model_specific::GsBase;
 
-#[repr(C)]
-pub struct PerCpu {
-    selfptr: *const PerCpu,
-    magic: u64,
+#[repr(C, align(64))]
+struct PerCpu {
+    /// Start of the area, which is where this struct lives
+    selfptr: *mut u8,
+    cpuid: u32,
+    /// Kernel stack the syscall entry switches to
     syscall_rsp: usize,
+    /// User stack pointer, saved by the syscall entry
     sysret_rsp: usize,
-    pub cpuid: u32,
 }
 
-const MAGIC: u64 = u64::from_le_bytes(*b"!percpu!");
+/// Bytes at the start of every area that belong to [PerCpu]
+pub const RESERVED: usize = size_of::<PerCpu>();
 
-static WAITING: AtomicU32 = AtomicU32::new(u32::MAX);
+// gs relative offsets for assembly
+pub const SYSCALL_RSP: usize = offset_of!(PerCpu, syscall_rsp);
+pub const SYSRET_RSP: usize = offset_of!(PerCpu, sysret_rsp);
+const SELFPTR: usize = offset_of!(PerCpu, selfptr);
+const CPUID: usize = offset_of!(PerCpu, cpuid);
 
+/// Points gs at this cpu's area, must be called once per cpu before it uses
+/// [cpuid] or [this_cpu_area]
 pub unsafe fn init(cpuid: u32) {
-    let _ = WAITING.compare_exchange(
-        u32::MAX,
-        boot::cpu_count(),
-        Ordering::AcqRel,
-        Ordering::Acquire,
-    );
-
-    let percpu = Box::leak(Box::new(PerCpu {
-        selfptr: ptr::null(),
-        magic: MAGIC,
-        syscall_rsp: 0,
-        sysret_rsp: 0,
-        cpuid,
-    }));
-    let percpu_ptr = percpu as *const PerCpu;
-    percpu.selfptr = percpu_ptr;
-
-    unsafe { GsBase::write_raw(percpu_ptr as usize as u64) };
-    WAITING.fetch_sub(1, Ordering::AcqRel);
+    let area = cpulocal::area(cpuid).as_ptr();
+    unsafe {
+        area.cast::<PerCpu>().write(PerCpu {
+            selfptr: area,
+            cpuid,
+            syscall_rsp: 0,
+            sysret_rsp: 0,
+        });
+        GsBase::write_raw(area as u64);
+    }
+}
+
+/// Returns true once the current cpu can use its percpu data
+///
+/// Checks gs itself, since nothing else can tell this cpu apart from the
+/// others until it points at an area.
+pub fn is_ready() -> bool {
+    let base = unsafe { GsBase::read_raw() };
+    cpulocal::is_area(base as usize)
 }
 
-pub fn get_percpu() -> &'static PerCpu {
-    loop {
-        let waiting = WAITING.load(Ordering::Acquire);
-        match waiting {
-            0 => break,
-            u32::MAX => panic!("attempted to get percpu without initilizing"),
-            _ => core::hint::spin_loop(),
-        }
+#[inline(always)]
+pub fn cpuid() -> u32 {
+    let cpuid: u32;
+    unsafe {
+        asm!(
+            "mov {:e}, gs:[{offset}]",
+            out(reg) cpuid,
+            offset = const CPUID,
+            options(nostack, preserves_flags, readonly, pure),
+        )
     }
+    cpuid
+}
 
-    let percpu_ptr = unsafe { GsBase::read_raw() } as usize as *const PerCpu;
-    assert_eq!(unsafe { (*percpu_ptr).selfptr }, percpu_ptr);
-    assert_eq!(unsafe { (*percpu_ptr).magic }, MAGIC);
-    unsafe { &*percpu_ptr }
+#[inline(always)]
+pub fn this_cpu_area() -> *mut u8 {
+    let area: *mut u8;
+    unsafe {
+        asm!(
+            "mov {}, gs:[{offset}]",
+            out(reg) area,
+            offset = const SELFPTR,
+            options(nostack, preserves_flags, readonly, pure),
+        )
+    }
+    area
 }
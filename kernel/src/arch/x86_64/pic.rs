@@ -0,0 +1,52 @@
+//! The legacy 8259 pics, only set up so they stay out of the way
+
+use super::port::{inb, outb};
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+/// Vectors the pics are moved to, between the exceptions and the dynamic vectors
+pub const MASTER_VECTOR: u8 = 32;
+pub const SLAVE_VECTOR: u8 = 40;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+
+/// Port 0x80 is unused, writing to it gives the pics time to settle
+fn io_wait() {
+    unsafe { outb(0x80, 0) };
+}
+
+/// Remaps the pics away from the exception vectors and masks every irq
+///
+/// A masked pic can still raise spurious irqs, remapping makes sure those
+/// can't be mistaken for exceptions.
+pub fn disable() {
+    let init = [
+        (ICW1_INIT | ICW1_ICW4, ICW1_INIT | ICW1_ICW4),
+        (MASTER_VECTOR, SLAVE_VECTOR),
+        // the slave is connected to irq 2 of the master
+        (1 << 2, 2),
+        (ICW4_8086, ICW4_8086),
+    ];
+
+    unsafe {
+        for (i, (master, slave)) in init.into_iter().enumerate() {
+            let (master_port, slave_port) = match i {
+                0 => (MASTER_COMMAND, SLAVE_COMMAND),
+                _ => (MASTER_DATA, SLAVE_DATA),
+            };
+            outb(master_port, master);
+            io_wait();
+            outb(slave_port, slave);
+            io_wait();
+        }
+
+        outb(MASTER_DATA, 0xff);
+        outb(SLAVE_DATA, 0xff);
+        inb(MASTER_DATA);
+    }
+}
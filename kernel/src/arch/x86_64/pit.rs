@@ -0,0 +1,41 @@
+//! The legacy programmable interval timer, only used to measure other timers
+
+use core::time::Duration;
+
+use super::port::{inb, outb};
+
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Controls the channel 2 gate and reads its output
+const SPEAKER: u16 = 0x61;
+
+const GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const OUTPUT: u8 = 1 << 5;
+
+/// Busy waits for `duration`, which must be less than about 54ms
+///
+/// Uses channel 2 so it doesn't interfere with anything wired to irq 0
+pub fn wait(duration: Duration) {
+    let ticks = duration.as_nanos() as u64 * FREQUENCY / 1_000_000_000;
+    let ticks: u16 = ticks.try_into().expect("pit wait is too long");
+
+    unsafe {
+        let speaker = inb(SPEAKER) & !(GATE | SPEAKER_ENABLE);
+        outb(SPEAKER, speaker);
+
+        // channel 2, lobyte/hibyte, interrupt on terminal count
+        outb(COMMAND, 0b1011_0000);
+        outb(CHANNEL_2, ticks as u8);
+        outb(CHANNEL_2, (ticks >> 8) as u8);
+
+        // counting starts once the gate goes high
+        outb(SPEAKER, speaker | GATE);
+        while inb(SPEAKER) & OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        outb(SPEAKER, speaker);
+    }
+}
@@ -1,5 +1,71 @@
 use core::arch::asm;
 
+use bitflags::bitflags;
+
+use crate::mem::{x86_64::PageTable, PhysPtr};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr0Flags: u64 {
+        const PROTECTED_MODE_ENABLE = 1 << 0;
+        const MONITOR_COPROCESSOR = 1 << 1;
+        const EMULATE_COPROCESSOR = 1 << 2;
+        const TASK_SWITCHED = 1 << 3;
+        const EXTENSION_TYPE = 1 << 4;
+        const NUMERIC_ERROR = 1 << 5;
+        const WRITE_PROTECT = 1 << 16;
+        const ALIGNMENT_MASK = 1 << 18;
+        const NOT_WRITE_THROUGH = 1 << 29;
+        const CACHE_DISABLE = 1 << 30;
+        const PAGING = 1 << 31;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr4Flags: u64 {
+        const VIRTUAL_8086_EXTENSIONS = 1 << 0;
+        const PROTECTED_VIRTUAL_INTERRUPTS = 1 << 1;
+        const TIMESTAMP_DISABLE = 1 << 2;
+        const DEBUGGING_EXTENSIONS = 1 << 3;
+        const PAGE_SIZE_EXTENSION = 1 << 4;
+        const PHYSICAL_ADDRESS_EXTENSION = 1 << 5;
+        const MACHINE_CHECK_EXCEPTION = 1 << 6;
+        const PAGE_GLOBAL = 1 << 7;
+        const PERFORMANCE_COUNTER = 1 << 8;
+        const OSFXSR = 1 << 9;
+        const OSXMMEXCPT = 1 << 10;
+        const USER_MODE_INSTRUCTION_PREVENTION = 1 << 11;
+        const L5_PAGING = 1 << 12;
+        const VIRTUAL_MACHINE_EXTENSIONS = 1 << 13;
+        const SAFER_MODE_EXTENSIONS = 1 << 14;
+        const FSGSBASE = 1 << 16;
+        const PCID = 1 << 17;
+        const OSXSAVE = 1 << 18;
+        const SUPERVISOR_MODE_EXECUTION_PROTECTION = 1 << 20;
+        const SUPERVISOR_MODE_ACCESS_PREVENTION = 1 << 21;
+        const PROTECTION_KEY_USER = 1 << 22;
+        const CONTROL_FLOW_ENFORCEMENT = 1 << 23;
+        const PROTECTION_KEY_SUPERVISOR = 1 << 24;
+    }
+}
+
+bitflags! {
+    /// State components enabled for xsave
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Xcr0Flags: u64 {
+        const X87 = 1 << 0;
+        const SSE = 1 << 1;
+        const AVX = 1 << 2;
+        const BNDREG = 1 << 3;
+        const BNDCSR = 1 << 4;
+        const OPMASK = 1 << 5;
+        const ZMM_HI256 = 1 << 6;
+        const HI16_ZMM = 1 << 7;
+        const PKRU = 1 << 9;
+    }
+}
+
 pub struct Cr0;
 impl Cr0 {
     pub fn read_raw() -> u64 {
@@ -9,7 +75,21 @@ impl Cr0 {
     }
 
     pub unsafe fn write_raw(val: u64) {
-        unsafe { asm!("mov cr3, {}", in(reg) val, options(nostack, preserves_flags)) }
+        unsafe { asm!("mov cr0, {}", in(reg) val, options(nostack, preserves_flags)) }
+    }
+
+    pub fn read() -> Cr0Flags {
+        Cr0Flags::from_bits_retain(Self::read_raw())
+    }
+
+    pub unsafe fn write(flags: Cr0Flags) {
+        unsafe { Self::write_raw(flags.bits()) }
+    }
+
+    pub unsafe fn update(f: impl FnOnce(&mut Cr0Flags)) {
+        let mut flags = Self::read();
+        f(&mut flags);
+        unsafe { Self::write(flags) }
     }
 }
 
@@ -24,10 +104,17 @@ impl Cr2 {
     pub unsafe fn write_raw(val: u64) {
         unsafe { asm!("mov cr2, {}", in(reg) val, options(nostack, preserves_flags)) }
     }
+
+    /// Returns the address of the last page fault
+    pub fn read() -> usize {
+        Self::read_raw() as usize
+    }
 }
 
 pub struct Cr3;
 impl Cr3 {
+    const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
     pub fn read_raw() -> u64 {
         let res: u64;
         unsafe { asm!("mov {}, cr3", out(reg) res, options(nomem, nostack, preserves_flags)) }
@@ -37,6 +124,21 @@ impl Cr3 {
     pub unsafe fn write_raw(val: u64) {
         unsafe { asm!("mov cr3, {}", in(reg) val, options(nostack, preserves_flags)) }
     }
+
+    /// Returns the root page table and the pcid, which holds the caching
+    /// flags instead when [Cr4Flags::PCID] is off
+    pub fn read() -> (PhysPtr<PageTable>, u16) {
+        let val = Self::read_raw();
+        (
+            PhysPtr::new((val & Self::ADDR_MASK) as usize),
+            (val & 0xfff) as u16,
+        )
+    }
+
+    pub unsafe fn write(table: PhysPtr<PageTable>, pcid: u16) {
+        assert!(pcid < 0x1000, "pcid is too big");
+        unsafe { Self::write_raw(table.addr() as u64 | pcid as u64) }
+    }
 }
 
 pub struct Cr4;
@@ -50,4 +152,60 @@ impl Cr4 {
     pub unsafe fn write_raw(val: u64) {
         unsafe { asm!("mov cr4, {}", in(reg) val, options(nostack, preserves_flags)) }
     }
+
+    pub fn read() -> Cr4Flags {
+        Cr4Flags::from_bits_retain(Self::read_raw())
+    }
+
+    pub unsafe fn write(flags: Cr4Flags) {
+        unsafe { Self::write_raw(flags.bits()) }
+    }
+
+    pub unsafe fn update(f: impl FnOnce(&mut Cr4Flags)) {
+        let mut flags = Self::read();
+        f(&mut flags);
+        unsafe { Self::write(flags) }
+    }
+}
+
+/// Only accessible once [Cr4Flags::OSXSAVE] is set
+pub struct Xcr0;
+impl Xcr0 {
+    pub fn read_raw() -> u64 {
+        let (high, low): (u32, u32);
+        unsafe {
+            asm!(
+                "xgetbv",
+                in("ecx") 0,
+                out("eax") low, out("edx") high,
+                options(nomem, nostack, preserves_flags),
+            )
+        }
+        ((high as u64) << 32) | (low as u64)
+    }
+
+    pub unsafe fn write_raw(val: u64) {
+        unsafe {
+            asm!(
+                "xsetbv",
+                in("ecx") 0,
+                in("eax") val as u32, in("edx") (val >> 32) as u32,
+                options(nostack, preserves_flags),
+            )
+        }
+    }
+
+    pub fn read() -> Xcr0Flags {
+        Xcr0Flags::from_bits_retain(Self::read_raw())
+    }
+
+    pub unsafe fn write(flags: Xcr0Flags) {
+        unsafe { Self::write_raw(flags.bits()) }
+    }
+
+    pub unsafe fn update(f: impl FnOnce(&mut Xcr0Flags)) {
+        let mut flags = Self::read();
+        f(&mut flags);
+        unsafe { Self::write(flags) }
+    }
 }
@@ -1,5 +1,9 @@
 use core::arch::asm;
 
+use bitflags::bitflags;
+
+use crate::mem::PhysPtr;
+
 pub unsafe fn read_msr(id: u32) -> u64 {
     let (high, low): (u32, u32);
     unsafe {
@@ -29,14 +33,126 @@ pub unsafe fn write_msr(id: u32, val: u64) {
 
 pub struct Msr<const N: u32>;
 impl<const N: u32> Msr<N> {
+    pub const ID: u32 = N;
+
     pub unsafe fn read_raw() -> u64 {
         read_msr(N)
     }
     pub unsafe fn write_raw(val: u64) {
         write_msr(N, val)
     }
+    pub unsafe fn update_raw(f: impl FnOnce(u64) -> u64) {
+        unsafe { Self::write_raw(f(Self::read_raw())) }
+    }
 }
 
+pub type ApicBase = Msr<0x1B>;
+pub type TscDeadline = Msr<0x6E0>;
+pub type Pat = Msr<0x277>;
+/// Machine check bank count and capabilities
+pub type McgCap = Msr<0x179>;
+pub type McgStatus = Msr<0x17A>;
+pub type McgCtl = Msr<0x17B>;
+pub type Efer = Msr<0xC000_0080>;
+/// Segments loaded by syscall and sysret
+pub type Star = Msr<0xC000_0081>;
+/// 64 bit syscall entry point
+pub type Lstar = Msr<0xC000_0082>;
+/// Compatibility mode syscall entry point
+pub type Cstar = Msr<0xC000_0083>;
+/// Rflags bits cleared by syscall
+pub type Sfmask = Msr<0xC000_0084>;
 pub type FsBase = Msr<0xC000_0100>;
 pub type GsBase = Msr<0xC000_0101>;
 pub type KernelGsBase = Msr<0xC000_0102>;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EferFlags: u64 {
+        const SYSTEM_CALL_EXTENSIONS = 1 << 0;
+        const LONG_MODE_ENABLE = 1 << 8;
+        const LONG_MODE_ACTIVE = 1 << 10;
+        const NO_EXECUTE_ENABLE = 1 << 11;
+        const SECURE_VIRTUAL_MACHINE_ENABLE = 1 << 12;
+        const LONG_MODE_SEGMENT_LIMIT_ENABLE = 1 << 13;
+        const FAST_FXSAVE_FXRSTOR = 1 << 14;
+        const TRANSLATION_CACHE_EXTENSION = 1 << 15;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ApicBaseFlags: u64 {
+        const BSP = 1 << 8;
+        const X2APIC_ENABLE = 1 << 10;
+        const ENABLE = 1 << 11;
+    }
+}
+
+impl Efer {
+    pub fn read() -> EferFlags {
+        EferFlags::from_bits_retain(unsafe { Self::read_raw() })
+    }
+
+    pub unsafe fn write(flags: EferFlags) {
+        unsafe { Self::write_raw(flags.bits()) }
+    }
+
+    pub unsafe fn update(f: impl FnOnce(&mut EferFlags)) {
+        let mut flags = Self::read();
+        f(&mut flags);
+        unsafe { Self::write(flags) }
+    }
+}
+
+impl ApicBase {
+    const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    /// Returns the physical address of the xapic registers and the flags
+    pub fn read() -> (PhysPtr<()>, ApicBaseFlags) {
+        let val = unsafe { Self::read_raw() };
+        (
+            PhysPtr::new((val & Self::ADDR_MASK) as usize),
+            ApicBaseFlags::from_bits_truncate(val),
+        )
+    }
+
+    pub unsafe fn write(base: PhysPtr<()>, flags: ApicBaseFlags) {
+        unsafe { Self::write_raw(base.addr() as u64 | flags.bits()) }
+    }
+
+    pub unsafe fn update(f: impl FnOnce(&mut ApicBaseFlags)) {
+        let (base, mut flags) = Self::read();
+        f(&mut flags);
+        unsafe { Self::write(base, flags) }
+    }
+}
+
+/// Memory types usable in the page attribute table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    Uncached = 7,
+}
+
+impl Pat {
+    /// Sets the memory type selected by a pat index
+    pub unsafe fn set(index: usize, kind: MemoryType) {
+        assert!(index < 8, "pat index out of range");
+        let shift = index * 8;
+        unsafe { Self::update_raw(|pat| (pat & !(0xff << shift)) | ((kind as u64) << shift)) }
+    }
+}
+
+impl Star {
+    /// Sets the selectors syscall and sysret load, sysret adds 16 to the
+    /// user base for cs and 8 for ss
+    pub unsafe fn write(kernel_code: u16, user_base: u16) {
+        unsafe { Self::write_raw(((user_base as u64) << 48) | ((kernel_code as u64) << 32)) }
+    }
+}
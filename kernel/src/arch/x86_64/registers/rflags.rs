@@ -0,0 +1,48 @@
+use core::arch::asm;
+
+use bitflags::bitflags;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct RFlags: u64 {
+        const CARRY = 1 << 0;
+        const PARITY = 1 << 2;
+        const AUXILIARY_CARRY = 1 << 4;
+        const ZERO = 1 << 6;
+        const SIGN = 1 << 7;
+        const TRAP = 1 << 8;
+        const INTERRUPT = 1 << 9;
+        const DIRECTION = 1 << 10;
+        const OVERFLOW = 1 << 11;
+        const IOPL_LOW = 1 << 12;
+        const IOPL_HIGH = 1 << 13;
+        const NESTED_TASK = 1 << 14;
+        const RESUME = 1 << 16;
+        const VIRTUAL_8086 = 1 << 17;
+        const ALIGNMENT_CHECK = 1 << 18;
+        const VIRTUAL_INTERRUPT = 1 << 19;
+        const VIRTUAL_INTERRUPT_PENDING = 1 << 20;
+        const ID = 1 << 21;
+    }
+}
+
+impl RFlags {
+    pub fn read_raw() -> u64 {
+        let res: u64;
+        unsafe { asm!("pushfq", "pop {}", out(reg) res, options(nomem, preserves_flags)) }
+        res
+    }
+
+    pub unsafe fn write_raw(val: u64) {
+        // not nomem, this can change IF
+        unsafe { asm!("push {}", "popfq", in(reg) val) }
+    }
+
+    pub fn read() -> Self {
+        Self::from_bits_retain(Self::read_raw())
+    }
+
+    pub unsafe fn write(flags: Self) {
+        unsafe { Self::write_raw(flags.bits()) }
+    }
+}
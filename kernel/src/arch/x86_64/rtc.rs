@@ -0,0 +1,390 @@
+//! The cmos real time clock, read at boot to find the wall clock time
+//!
+//! It can also interrupt periodically or at an alarm time, through isa irq 8.
+
+use alloc::boxed::Box;
+use spin::Once;
+
+use crate::{acpi::fadt, println, sync::IrqSpinlock, time::DateTime};
+
+use super::{
+    interrupts, ioapic,
+    port::{inb, outb},
+};
+
+/// Selects a register, its top bit masks nmis and is left clear
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+/// Interrupt flags, reading it acknowledges the interrupt
+const STATUS_C: u8 = 0x0c;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RATE_MASK: u8 = 0x0f;
+
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const UPDATE_INTERRUPT: u8 = 1 << 4;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+
+const ALARM_FLAG: u8 = 1 << 5;
+const PERIODIC_FLAG: u8 = 1 << 6;
+const INTERRUPT_FLAG: u8 = 1 << 7;
+
+/// Set in the hours register for pm in 12 hour mode
+const HOUR_PM: u8 = 1 << 7;
+
+const IRQ: u8 = 8;
+/// The periodic interrupt divides this down by a power of two
+const BASE_FREQUENCY: u32 = 32768;
+/// Rates 1 and 2 don't give the frequencies the formula says on every chip
+const MAX_FREQUENCY: u32 = 8192;
+
+struct Rtc {
+    binary: bool,
+    hour_24: bool,
+    /// Cmos index of the century, from the fadt
+    century: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RtcError {
+    NotPresent,
+    /// Only powers of two from 2 to 8192 Hz are supported
+    InvalidFrequency(u32),
+    InvalidTime,
+}
+
+/// Only gives access to the registers while locked
+struct Cmos(());
+
+static CMOS: IrqSpinlock<Cmos> = IrqSpinlock::new(Cmos(()));
+static RTC: Once<Option<Rtc>> = Once::new();
+
+type PeriodicCallback = Box<dyn Fn() + Send + Sync>;
+type AlarmCallback = Box<dyn FnOnce() + Send>;
+
+static PERIODIC: IrqSpinlock<Option<PeriodicCallback>> = IrqSpinlock::new(None);
+static ALARM: IrqSpinlock<Option<AlarmCallback>> = IrqSpinlock::new(None);
+
+impl Cmos {
+    fn read(&mut self, reg: u8) -> u8 {
+        unsafe {
+            outb(INDEX, reg);
+            inb(DATA)
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        unsafe {
+            outb(INDEX, reg);
+            outb(DATA, val);
+        }
+    }
+}
+
+fn with_cmos<T>(f: impl FnOnce(&mut Cmos) -> T) -> T {
+    f(&mut CMOS.lock())
+}
+
+fn rtc() -> Result<&'static Rtc, RtcError> {
+    RTC.get()
+        .expect("rtc isn't initialized")
+        .as_ref()
+        .ok_or(RtcError::NotPresent)
+}
+
+/// Disables the rtc's interrupts and routes its irq to the bsp, called once
+/// on the bsp after the io apic is set up
+pub fn init() {
+    crate::assert_once!();
+
+    RTC.call_once(|| {
+        let fadt = fadt::get();
+        if fadt.is_some_and(|fadt| !fadt.cmos_rtc) {
+            println!("rtc: not present");
+            return None;
+        }
+
+        let status = with_cmos(|cmos| {
+            let status =
+                cmos.read(STATUS_B) & !(UPDATE_INTERRUPT | ALARM_INTERRUPT | PERIODIC_INTERRUPT);
+            cmos.write(STATUS_B, status);
+            cmos.read(STATUS_C);
+            status
+        });
+
+        let vector = interrupts::allocate_vector().expect("no free vector for the rtc");
+        interrupts::register_handler(vector, |_| handle_interrupt());
+        if let Err(err) = ioapic::route_isa_irq(IRQ, vector, 0) {
+            println!("rtc: can't route irq {IRQ}: {err:?}");
+        }
+
+        Some(Rtc {
+            binary: status & BINARY != 0,
+            hour_24: status & HOUR_24 != 0,
+            century: fadt.and_then(|fadt| fadt.century),
+        })
+    });
+}
+
+/// Reads the date and time, `None` if there is no rtc or it holds garbage
+///
+/// May spin for a couple of milliseconds with interrupts disabled while the
+/// rtc updates.
+pub fn read() -> Option<DateTime> {
+    let rtc = rtc().ok()?;
+
+    // the registers can change between reads, so read until two agree
+    let raw = with_cmos(|cmos| {
+        let mut last = read_raw(rtc, cmos);
+        loop {
+            let next = read_raw(rtc, cmos);
+            if next == last {
+                break next;
+            }
+            last = next;
+        }
+    });
+
+    let [second, minute, hour, day, month, year, century] = raw;
+    let hour = rtc.decode_hour(hour);
+    let century = match rtc.century {
+        Some(_) => rtc.decode(century) as i64,
+        // the firmware doesn't keep it, assume the current one
+        None => 20,
+    };
+
+    let date = DateTime {
+        year: century * 100 + rtc.decode(year) as i64,
+        month: rtc.decode(month),
+        day: rtc.decode(day),
+        hour,
+        minute: rtc.decode(minute),
+        second: rtc.decode(second),
+    };
+    let valid = (1..=12).contains(&date.month)
+        && (1..=31).contains(&date.day)
+        && date.hour < 24
+        && date.minute < 60
+        && date.second < 60;
+    if !valid {
+        println!("rtc: invalid time {date:?}");
+        return None;
+    }
+    Some(date)
+}
+
+/// Reads the time registers once no update is in progress
+///
+/// An update takes under 2ms, and only starts right after the flag is set.
+fn read_raw(rtc: &Rtc, cmos: &mut Cmos) -> [u8; 7] {
+    while cmos.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        cmos.read(SECONDS),
+        cmos.read(MINUTES),
+        cmos.read(HOURS),
+        cmos.read(DAY),
+        cmos.read(MONTH),
+        cmos.read(YEAR),
+        rtc.century.map_or(0, |index| cmos.read(index)),
+    ]
+}
+
+impl Rtc {
+    fn decode(&self, val: u8) -> u8 {
+        if self.binary {
+            val
+        } else {
+            (val >> 4) * 10 + (val & 0x0f)
+        }
+    }
+
+    fn encode(&self, val: u8) -> u8 {
+        if self.binary {
+            val
+        } else {
+            ((val / 10) << 4) | (val % 10)
+        }
+    }
+
+    fn decode_hour(&self, val: u8) -> u8 {
+        let hour = self.decode(val & !HOUR_PM);
+        if self.hour_24 {
+            hour
+        } else {
+            // 12 is midnight or noon
+            hour % 12 + if val & HOUR_PM != 0 { 12 } else { 0 }
+        }
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        if self.hour_24 {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { HOUR_PM } else { 0 };
+        let hour = match hour % 12 {
+            0 => 12,
+            hour => hour,
+        };
+        self.encode(hour) | pm
+    }
+}
+
+fn handle_interrupt() -> bool {
+    let flags = with_cmos(|cmos| cmos.read(STATUS_C));
+    if flags & INTERRUPT_FLAG == 0 {
+        return false;
+    }
+
+    if flags & PERIODIC_FLAG != 0 {
+        if let Some(callback) = &*PERIODIC.lock() {
+            callback();
+        }
+    }
+    if flags & ALARM_FLAG != 0 {
+        // the alarm would go off again the next day
+        with_cmos(|cmos| {
+            let status = cmos.read(STATUS_B);
+            cmos.write(STATUS_B, status & !ALARM_INTERRUPT);
+        });
+        let callback = ALARM.lock().take();
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+    true
+}
+
+/// Calls `callback` `hz` times a second on the bsp, replacing any previous one
+///
+/// The callback runs in the interrupt handler and must not call back into
+/// this module.
+pub fn set_periodic(hz: u32, callback: impl Fn() + Send + Sync + 'static) -> Result<(), RtcError> {
+    rtc()?;
+    if !hz.is_power_of_two() || !(2..=MAX_FREQUENCY).contains(&hz) {
+        return Err(RtcError::InvalidFrequency(hz));
+    }
+    let rate = (BASE_FREQUENCY / hz).trailing_zeros() as u8 + 1;
+
+    *PERIODIC.lock() = Some(Box::new(callback));
+    with_cmos(|cmos| {
+        let status = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status & !RATE_MASK) | rate);
+        let status = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status | PERIODIC_INTERRUPT);
+    });
+    Ok(())
+}
+
+pub fn stop_periodic() {
+    if rtc().is_err() {
+        return;
+    }
+    with_cmos(|cmos| {
+        let status = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status & !PERIODIC_INTERRUPT);
+    });
+    // dropped here, outside the lock
+    let _callback = PERIODIC.lock().take();
+}
+
+/// Calls `callback` once on the bsp, the next time the clock reads the given
+/// time of day, replacing any pending alarm
+///
+/// The callback runs in the interrupt handler and must not call back into
+/// this module.
+pub fn set_alarm(
+    hour: u8,
+    minute: u8,
+    second: u8,
+    callback: impl FnOnce() + Send + 'static,
+) -> Result<(), RtcError> {
+    let rtc = rtc()?;
+    if hour >= 24 || minute >= 60 || second >= 60 {
+        return Err(RtcError::InvalidTime);
+    }
+
+    *ALARM.lock() = Some(Box::new(callback));
+    with_cmos(|cmos| {
+        cmos.write(HOURS_ALARM, rtc.encode_hour(hour));
+        cmos.write(MINUTES_ALARM, rtc.encode(minute));
+        cmos.write(SECONDS_ALARM, rtc.encode(second));
+        let status = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status | ALARM_INTERRUPT);
+    });
+    Ok(())
+}
+
+pub fn cancel_alarm() {
+    if rtc().is_err() {
+        return;
+    }
+    with_cmos(|cmos| {
+        let status = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status & !ALARM_INTERRUPT);
+    });
+    // dropped here, outside the lock
+    let _callback = ALARM.lock().take();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RTC_MODES: [(bool, bool); 4] =
+        [(false, false), (false, true), (true, false), (true, true)];
+
+    #[test]
+    fn bcd() {
+        let rtc = Rtc {
+            binary: false,
+            hour_24: true,
+            century: None,
+        };
+        assert_eq!(rtc.decode(0x59), 59);
+        assert_eq!(rtc.encode(59), 0x59);
+    }
+
+    #[test]
+    fn hour_round_trip() {
+        for (binary, hour_24) in RTC_MODES {
+            let rtc = Rtc {
+                binary,
+                hour_24,
+                century: None,
+            };
+            for hour in 0..24 {
+                assert_eq!(rtc.decode_hour(rtc.encode_hour(hour)), hour);
+            }
+        }
+    }
+
+    #[test]
+    fn hour_12() {
+        let rtc = Rtc {
+            binary: false,
+            hour_24: false,
+            century: None,
+        };
+        assert_eq!(rtc.decode_hour(0x12), 0);
+        assert_eq!(rtc.decode_hour(0x12 | HOUR_PM), 12);
+        assert_eq!(rtc.decode_hour(0x11 | HOUR_PM), 23);
+        assert_eq!(rtc.encode_hour(0), 0x12);
+        assert_eq!(rtc.encode_hour(13), 0x01 | HOUR_PM);
+    }
+}
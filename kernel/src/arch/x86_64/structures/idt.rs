@@ -6,8 +6,6 @@ use core::{
 use bit_field::BitField;
 use spin::Lazy;
 
-use crate::println;
-
 use super::{gdt::KERNEL_CODE, DescriptorTablePointer};
 
 global_asm!(include_str!("isr.asm"));
@@ -16,58 +14,11 @@ extern "C" {
     static isr_table: [usize; 256];
 }
 
-#[no_mangle]
-extern "C" fn isr_inner(data: &IsrData) {
-    const EXCEPTIONS: [&str; 32] = [
-        "division error",
-        "debug exception",
-        "non maskable interrupt",
-        "breakpoint",
-        "overflow",
-        "bound range exceeded",
-        "invalid opcode",
-        "device not available",
-        "double fault",
-        "coprocessor segment overrun",
-        "invalid tss",
-        "segment not present",
-        "stack segment fault",
-        "general protection fault",
-        "page fault",
-        "",
-        "x87 floating point exception",
-        "alignment check",
-        "machine check",
-        "simd floating point exception",
-        "virtualiztion exception",
-        "control protection exception",
-        "",
-        "",
-        "",
-        "",
-        "",
-        "",
-        "hypervisor injection exception",
-        "vmm communication exception",
-        "security exception",
-        "",
-    ];
-
-    let rip = data.instruction as usize;
-    let vector = data.vector;
-    let err = data.err_code;
-
-    match vector {
-        3 => println!("int {vector}: {}\n\trip: {rip:#x}", EXCEPTIONS[vector]),
-        8 | 10..=14 | 17 | 21 | 29 => panic!(
-            "int {vector} ({err}): {}\n\trip: {rip:#x}",
-            EXCEPTIONS[vector]
-        ),
-        0..32 => panic!("int {vector}: {}\n\trip: {rip:#x}", EXCEPTIONS[vector]),
-        32..256 => println!("int {vector}"),
-        256.. => unreachable!(),
-    }
-}
+// interrupt stack table indices, every cpu's tss has its own stacks for these
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const NMI_IST: u8 = 2;
+pub const MACHINE_CHECK_IST: u8 = 3;
+pub const DEBUG_IST: u8 = 4;
 
 #[repr(transparent)]
 pub struct InterruptDescriptorTable {
@@ -85,19 +36,6 @@ struct InterruptDescriptorTableEntry {
     _reserved: u32,
 }
 
-#[derive(Debug)]
-#[repr(C)]
-struct IsrData {
-    registers: [usize; 16],
-    vector: usize,
-    err_code: usize,
-    instruction: *const u8,
-    code_segment: usize,
-    flags: usize,
-    stack_pointer: *const usize,
-    stack_segment: usize,
-}
-
 pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(InterruptDescriptorTable::new);
 
 impl InterruptDescriptorTable {
@@ -108,7 +46,13 @@ impl InterruptDescriptorTable {
                 InterruptDescriptorTableEntry {
                     isr_low: isr.get_bits(0..16) as u16,
                     kernel_code_segment: KERNEL_CODE,
-                    ist: 0,
+                    ist: match i {
+                        1 => DEBUG_IST,
+                        2 => NMI_IST,
+                        8 => DOUBLE_FAULT_IST,
+                        18 => MACHINE_CHECK_IST,
+                        _ => 0,
+                    },
                     attributes: 0x8e,
                     isr_mid: isr.get_bits(16..32) as u16,
                     isr_high: isr.get_bits(32..64) as u32,
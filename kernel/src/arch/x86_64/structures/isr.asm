@@ -43,17 +43,17 @@
     2:
 .endmacro
 
-.macro def_isr_stub n
+.macro def_isr_stub n, entry=isr_main
     isr_stub_\n:
     push 0
     push \n
-    jmp isr_main
+    jmp \entry
 .endmacro
 
-.macro def_isr_stub_err n
+.macro def_isr_stub_err n, entry=isr_main
     isr_stub_\n:
     push \n
-    jmp isr_main
+    jmp \entry
 .endmacro
 
 .section .text
@@ -72,15 +72,43 @@ isr_main:
     add rsp, 16
     iretq
 
+// for the vectors on their own ist stacks, which can also arrive in kernel
+// mode right around a swapgs, so cs doesn't tell which gs is loaded. the
+// kernel's gs base is in the higher half and user mode can't change its own,
+// so the sign of the gs base msr does.
+isr_paranoid:
+    push_all
+    mov ecx, 0xC0000101 // gs base
+    rdmsr
+    xor ebx, ebx
+    test edx, edx
+    js 2f
+    swapgs
+    mov ebx, 1
+    2:
+    mov rdi, rsp
+    sub rsp, 8 // alignment
+    cld
+    call isr_inner
+    add rsp, 8
+    // rbx is callee saved, so it still says whether to swap back
+    test ebx, ebx
+    jz 2f
+    swapgs
+    2:
+    pop_all
+    add rsp, 16
+    iretq
+
 def_isr_stub 0
-def_isr_stub 1
-def_isr_stub 2
+def_isr_stub 1, isr_paranoid
+def_isr_stub 2, isr_paranoid
 def_isr_stub 3
 def_isr_stub 4
 def_isr_stub 5
 def_isr_stub 6
 def_isr_stub 7
-def_isr_stub_err 8
+def_isr_stub_err 8, isr_paranoid
 def_isr_stub 9
 def_isr_stub_err 10
 def_isr_stub_err 11
@@ -90,7 +118,7 @@ def_isr_stub_err 14
 def_isr_stub 15
 def_isr_stub 16
 def_isr_stub_err 17
-def_isr_stub 18
+def_isr_stub 18, isr_paranoid
 def_isr_stub 19
 def_isr_stub 20
 def_isr_stub_err 21
@@ -1,10 +1,18 @@
-use core::ptr;
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
 use alloc::boxed::Box;
 use gdt::GlobalDescriptorTable;
-use idt::IDT;
+use idt::{DEBUG_IST, DOUBLE_FAULT_IST, IDT, MACHINE_CHECK_IST, NMI_IST};
 use tss::TaskStateSegment;
 
+use crate::{
+    cpulocal::CpuLocal,
+    stack::{Stack, StackSize},
+};
+
 pub mod gdt;
 pub mod idt;
 pub mod tss;
@@ -26,9 +34,44 @@ impl<T> DescriptorTablePointer<T> {
     }
 }
 
-pub fn init() {
-    let tss = Box::leak(Box::new(TaskStateSegment::new()));
-    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new(tss)));
+static TSS: CpuLocal<AtomicPtr<TaskStateSegment>> =
+    CpuLocal::new(|_| AtomicPtr::new(ptr::null_mut()));
+
+/// Returns where this cpu's tss keeps rsp0, the stack interrupts from ring 3
+/// start on
+///
+/// The tss is packed, so the slot is only 4 byte aligned and must not be
+/// dereferenced, see [set_kernel_stack].
+pub fn kernel_stack_slot() -> *mut *mut () {
+    let tss = TSS.local().load(Ordering::Relaxed);
+    assert!(!tss.is_null(), "tss isn't initialized");
+    unsafe { ptr::addr_of_mut!((*tss).privilege_stack_table).cast() }
+}
+
+/// Sets this cpu's rsp0
+pub fn set_kernel_stack(rsp: *mut ()) {
+    unsafe { kernel_stack_slot().write_unaligned(rsp) }
+}
+
+pub fn init(cpuid: u32) {
+    let mut tss = TaskStateSegment::new();
+    for (ist, name) in [
+        (DOUBLE_FAULT_IST, "double fault stack"),
+        (NMI_IST, "nmi stack"),
+        (MACHINE_CHECK_IST, "machine check stack"),
+        (DEBUG_IST, "debug stack"),
+    ] {
+        let stack = Box::leak(Box::new(
+            Stack::with_size(StackSize::Small).expect("critical mapping failed"),
+        ));
+        stack.set_owner(name, Some(cpuid));
+        tss.interrupt_stack_table[ist as usize - 1] = stack.stack_pointer() as *mut ();
+    }
+
+    // written through this pointer later, so no reference may outlive this
+    let tss = Box::into_raw(Box::new(tss));
+    TSS.get(cpuid).store(tss, Ordering::Relaxed);
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new(unsafe { &*tss })));
     gdt.load();
 
     IDT.load();
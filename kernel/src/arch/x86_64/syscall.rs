@@ -0,0 +1,169 @@
+//! Fast system calls with syscall and sysret
+//!
+//! The syscall number is passed in rax and the arguments in rdi, rsi, rdx, r10,
+//! r8 and r9. The result is returned in rax, rcx and r11 are clobbered.
+
+use core::arch::global_asm;
+
+use crate::{assert_once_percpu, mem::USER_END};
+
+use super::{
+    percpu,
+    registers::{
+        model_specific::{Efer, EferFlags, Lstar, Sfmask, Star},
+        rflags::RFlags,
+    },
+    structures::gdt::{KERNEL_CODE, USER_CODE, USER_DATA},
+    user::{self, UserExit},
+};
+
+/// User state saved by the syscall entry, the last five fields are laid out
+/// like an interrupt frame
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    /// The syscall number, and the result on return
+    pub rax: usize,
+    pub rdi: usize,
+    pub rsi: usize,
+    pub rdx: usize,
+    pub r10: usize,
+    pub r8: usize,
+    pub r9: usize,
+    pub rbx: usize,
+    pub rbp: usize,
+    pub r12: usize,
+    pub r13: usize,
+    pub r14: usize,
+    pub r15: usize,
+    pub rip: usize,
+    code_segment: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    stack_segment: usize,
+}
+
+/// Errors returned to user code as negative numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(isize)]
+pub enum SyscallError {
+    /// No syscall has this number
+    NoSys = -1,
+}
+
+type Syscall = fn(&mut SyscallFrame) -> Result<usize, SyscallError>;
+
+pub const SYS_EXIT: usize = 0;
+
+static TABLE: [Syscall; 1] = [sys_exit];
+
+extern "C" {
+    fn syscall_entry();
+}
+
+global_asm!(
+    "
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{sysret_rsp}], rsp
+    mov rsp, gs:[{syscall_rsp}]
+
+    push {user_data}
+    push qword ptr gs:[{sysret_rsp}]
+    push r11
+    push {user_code}
+    push rcx
+    push r15
+    push r14
+    push r13
+    push r12
+    push rbp
+    push rbx
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+
+    mov rdi, rsp
+    xor ebp, ebp
+    sti
+    call syscall_inner
+    cli
+
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop rbx
+    pop rbp
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop rcx
+    add rsp, 8
+    pop r11
+    pop rsp
+    swapgs
+    sysretq
+",
+    sysret_rsp = const percpu::SYSRET_RSP,
+    syscall_rsp = const percpu::SYSCALL_RSP,
+    user_data = const USER_DATA,
+    user_code = const USER_CODE,
+);
+
+/// Programs the syscall msrs of this cpu
+pub fn init(cpuid: u32) {
+    assert_once_percpu!(cpuid);
+
+    unsafe {
+        // sysret loads ss from 8 and cs from 16 above the user base
+        Star::write(KERNEL_CODE, USER_DATA - 8);
+        Lstar::write_raw(syscall_entry as usize as u64);
+        Sfmask::write_raw(
+            (RFlags::INTERRUPT
+                | RFlags::DIRECTION
+                | RFlags::TRAP
+                | RFlags::ALIGNMENT_CHECK
+                | RFlags::NESTED_TASK)
+                .bits(),
+        );
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_inner(frame: &mut SyscallFrame) {
+    let result = match TABLE.get(frame.rax) {
+        Some(syscall) => syscall(frame),
+        None => Err(SyscallError::NoSys),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(err) => err as isize as usize,
+    };
+
+    // sysret faults in ring 0 on a non canonical rip, with the user's stack and
+    // gs, and so would iretq, so a syscall at the very top of user space can
+    // only end the program
+    if frame.rip >= USER_END {
+        user::exit(UserExit::Exception {
+            vector: 13,
+            err_code: 0,
+            rip: frame.rip,
+            fault_addr: None,
+        });
+    }
+}
+
+fn sys_exit(frame: &mut SyscallFrame) -> Result<usize, SyscallError> {
+    user::exit(UserExit::Exit { code: frame.rdi })
+}
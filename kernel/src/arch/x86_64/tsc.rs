@@ -0,0 +1,215 @@
+//! The time stamp counter, the kernel's clock source
+//!
+//! The frequency comes from cpuid when the cpu reports it, and is measured
+//! against the hpet or the pit otherwise. Every cpu measures its offset from the first one
+//! while booting, so readings from different cpus can be compared.
+
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::{Mutex, Once};
+
+use crate::{assert_once_percpu, boot, cpulocal::CpuLocal, println};
+
+use super::{
+    cpuid::{self, CpuFeatures},
+    hpet, pit, try_get_cpuid,
+};
+
+/// How long each hpet or pit measurement takes
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+const CALIBRATION_RUNS: usize = 5;
+
+/// Round trips each cpu makes to measure its offset, the fastest one is used
+const SYNC_ROUNDS: usize = 16;
+/// Offsets above this many cycles are reported
+const SYNC_WARN_CYCLES: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Crystal clock ratio from cpuid leaf 0x15
+    CpuidCrystal,
+    /// Base frequency from cpuid leaf 0x16
+    CpuidBase,
+    Hpet,
+    Pit,
+}
+
+struct Calibration {
+    khz: u64,
+    /// Counter of the first cpu when it was calibrated
+    boot: u64,
+}
+
+static CALIBRATION: Once<Calibration> = Once::new();
+/// Added to this cpu's counter to get the first cpu's
+static OFFSET: CpuLocal<AtomicI64> = CpuLocal::new(|_| AtomicI64::new(0));
+
+/// Held by the cpu measuring its offset
+static SYNC_TURN: Mutex<()> = Mutex::new(());
+static PING: AtomicBool = AtomicBool::new(false);
+/// The first cpu's counter in reply to [PING], zero until it replies
+static PONG: AtomicU64 = AtomicU64::new(0);
+
+/// Reads this cpu's counter, without the offset
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Returns true if the counter runs at a constant rate in every power state
+pub fn is_invariant() -> bool {
+    cpuid::has(CpuFeatures::INVARIANT_TSC)
+}
+
+/// Calibrates the counter on the first cpu and lines up the others with it
+pub fn init(cpuid: u32) {
+    assert_once_percpu!(cpuid);
+
+    if cpuid == 0 {
+        CALIBRATION.call_once(|| {
+            let (khz, source) = calibrate();
+            println!(
+                "tsc: {} kHz from {source:?}, {}",
+                khz,
+                if is_invariant() {
+                    "invariant"
+                } else {
+                    "not invariant"
+                }
+            );
+            Calibration { khz, boot: read() }
+        });
+        serve_sync();
+    } else {
+        sync(cpuid);
+    }
+}
+
+fn calibrate() -> (u64, Source) {
+    let info = cpuid::info();
+
+    if info.max_leaf >= 0x15 {
+        let leaf = cpuid::leaf(0x15);
+        // denominator, numerator and crystal hz
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            let khz = leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64 / 1000;
+            return (khz, Source::CpuidCrystal);
+        }
+    }
+
+    // the base frequency is only nominal, measure it when there is an hpet
+    if let Some(khz) = calibrate_hpet() {
+        return (khz, Source::Hpet);
+    }
+    if info.max_leaf >= 0x16 {
+        let mhz = cpuid::leaf(0x16).eax as u64;
+        if mhz != 0 {
+            return (mhz * 1000, Source::CpuidBase);
+        }
+    }
+
+    let mut runs = [0; CALIBRATION_RUNS];
+    for run in &mut runs {
+        let start = read();
+        pit::wait(CALIBRATION_TIME);
+        *run = (read() - start) / CALIBRATION_TIME.as_millis() as u64;
+    }
+    runs.sort_unstable();
+    (runs[CALIBRATION_RUNS / 2], Source::Pit)
+}
+
+/// Counts cycles over a few hpet intervals, `None` without an hpet
+fn calibrate_hpet() -> Option<u64> {
+    let ticks = CALIBRATION_TIME.as_nanos() as u64 * hpet::frequency()? / 1_000_000_000;
+
+    let mut runs = [0; CALIBRATION_RUNS];
+    for run in &mut runs {
+        let start_counter = hpet::counter()?;
+        let start = read();
+        let end_counter = loop {
+            let counter = hpet::counter()?;
+            if counter.wrapping_sub(start_counter) >= ticks {
+                break counter;
+            }
+            core::hint::spin_loop();
+        };
+        let cycles = read() - start;
+        *run = cycles * 1_000_000 / hpet::ticks_to_nanos(end_counter.wrapping_sub(start_counter))?;
+    }
+    runs.sort_unstable();
+    Some(runs[CALIBRATION_RUNS / 2])
+}
+
+/// Answers the other cpus' pings until they all measured their offset
+fn serve_sync() {
+    let pings = (boot::cpu_count() as usize - 1) * SYNC_ROUNDS;
+    for _ in 0..pings {
+        while !PING.swap(false, Ordering::AcqRel) {
+            core::hint::spin_loop();
+        }
+        PONG.store(read(), Ordering::Release);
+    }
+}
+
+fn sync(cpuid: u32) {
+    let _turn = SYNC_TURN.lock();
+
+    let mut best_rtt = u64::MAX;
+    let mut offset = 0;
+    for _ in 0..SYNC_ROUNDS {
+        PONG.store(0, Ordering::Release);
+        let start = read();
+        PING.store(true, Ordering::Release);
+        let reference = loop {
+            match PONG.load(Ordering::Acquire) {
+                0 => core::hint::spin_loop(),
+                reference => break reference,
+            }
+        };
+        let end = read();
+
+        // assume the reply was read halfway through the round trip
+        if end - start < best_rtt {
+            best_rtt = end - start;
+            offset = reference as i64 - (start + (end - start) / 2) as i64;
+        }
+    }
+
+    OFFSET.get(cpuid).store(offset, Ordering::Relaxed);
+    if offset.unsigned_abs() > SYNC_WARN_CYCLES {
+        println!("tsc: cpu {cpuid} is off by {offset} cycles");
+    }
+}
+
+/// Returns the counter frequency, in kHz
+pub fn khz() -> u64 {
+    CALIBRATION.get().expect("tsc isn't calibrated").khz
+}
+
+/// Reads the counter, adjusted to match the first cpu's
+pub fn read_synced() -> u64 {
+    // percpu data isn't ready during boot, offsets are small anyway
+    let offset = try_get_cpuid().map_or(0, |cpuid| OFFSET.get(cpuid).load(Ordering::Relaxed));
+    read().wrapping_add_signed(offset)
+}
+
+/// Converts a number of cycles into nanoseconds
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    (cycles as u128 * 1_000_000 / khz() as u128) as u64
+}
+
+/// Converts nanoseconds into a number of cycles
+pub fn nanos_to_cycles(nanos: u64) -> u64 {
+    (nanos as u128 * khz() as u128 / 1_000_000) as u64
+}
+
+/// Nanoseconds since the first cpu calibrated the counter, zero before that
+pub fn nanos_since_boot() -> u64 {
+    let Some(calibration) = CALIBRATION.get() else {
+        return 0;
+    };
+    cycles_to_nanos(read_synced().saturating_sub(calibration.boot))
+}
@@ -0,0 +1,211 @@
+//! Running code in ring 3
+//!
+//! [enter_user] saves the kernel's callee saved registers on the current stack
+//! and points rsp0 and the syscall stack just below them, so interrupts and
+//! syscalls from user mode run on the same stack without clobbering it. Leaving user mode jumps back to the saved
+//! registers, which makes [enter_user] return.
+
+use core::{
+    arch::global_asm,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpulocal::CpuLocal,
+    mem::{AddressSpace, Mapper, USER_END},
+    stack,
+    sync::IrqSpinlock,
+};
+
+use super::{
+    fpu::{self, FpuState},
+    frame_pointer, get_cpuid,
+    interrupts::IsrData,
+    percpu,
+    registers::control::{Cr2, Cr3},
+    structures::{
+        self,
+        gdt::{USER_CODE, USER_DATA},
+    },
+};
+
+/// Why user code stopped running
+#[derive(Debug, Clone, Copy)]
+pub enum UserExit {
+    /// An exception no handler dealt with
+    Exception {
+        vector: u8,
+        err_code: usize,
+        rip: usize,
+        /// The faulting address of a page fault
+        fault_addr: Option<usize>,
+    },
+    /// The exit syscall
+    Exit { code: usize },
+}
+
+struct Context {
+    /// Kernel stack pointer saved by [enter_user], zero while in the kernel
+    kernel_rsp: AtomicUsize,
+    exit: IrqSpinlock<Option<UserExit>>,
+}
+
+static CONTEXT: CpuLocal<Context> = CpuLocal::new(|_| Context {
+    kernel_rsp: AtomicUsize::new(0),
+    exit: IrqSpinlock::new(None),
+});
+
+/// Interrupts enabled, everything else cleared
+const USER_RFLAGS: u64 = 0x202;
+
+extern "C" {
+    fn user_enter(entry: usize, stack: usize, saved_rsp: *mut usize, rsp0: *mut *mut ());
+    fn user_return(saved_rsp: usize) -> !;
+}
+
+global_asm!(
+    "
+.global user_enter
+user_enter:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rdx], rsp
+    mov [rcx], rsp
+    mov gs:[{syscall_rsp}], rsp
+
+    cli
+    push {user_data}
+    push rsi
+    push {rflags}
+    push {user_code}
+    push rdi
+
+    // don't leak kernel values to user code
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    swapgs
+    iretq
+
+.global user_return
+user_return:
+    mov rsp, rdi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+",
+    user_data = const USER_DATA,
+    user_code = const USER_CODE,
+    rflags = const USER_RFLAGS,
+    syscall_rsp = const percpu::SYSCALL_RSP,
+);
+
+/// Runs user code at `entry` with the stack pointer `stack` in `address_space`,
+/// until it leaves user mode
+///
+/// The program's simd registers live in `fpu`, which keeps them between calls.
+///
+/// Must be called on a kernel [stack::Stack], interrupts from user mode run on
+/// it below this call.
+///
+/// # Safety
+/// `entry` and `stack` must be mapped in `address_space`
+pub unsafe fn enter_user(
+    entry: usize,
+    stack: usize,
+    address_space: &AddressSpace,
+    fpu: &mut FpuState,
+) -> UserExit {
+    assert!(entry < USER_END, "entry isn't a user address");
+    assert!(stack < USER_END, "stack isn't a user address");
+    assert!(
+        stack::find(frame_pointer()).is_some(),
+        "user mode must be entered from a kernel stack"
+    );
+
+    // nothing moves a thread to another cpu
+    let context = unsafe { CONTEXT.force() };
+    assert!(
+        context.kernel_rsp.load(Ordering::Relaxed) == 0,
+        "already running user code"
+    );
+
+    let cpuid = get_cpuid();
+    let (kernel_table, pcid) = Cr3::read();
+    address_space.mark_loaded(cpuid);
+    unsafe {
+        fpu::switch_to(fpu);
+        Cr3::write(address_space.ptroot(), 0);
+        user_enter(
+            entry,
+            stack,
+            context.kernel_rsp.as_ptr(),
+            structures::kernel_stack_slot(),
+        );
+        Cr3::write(kernel_table, pcid);
+        fpu::switch_to(ptr::null_mut());
+    }
+    address_space.mark_unloaded(cpuid);
+    structures::set_kernel_stack(ptr::null_mut());
+    context.kernel_rsp.store(0, Ordering::Relaxed);
+
+    context
+        .exit
+        .lock()
+        .take()
+        .expect("left user mode without a reason")
+}
+
+/// Returns true if the interrupted code was running in ring 3
+pub fn is_user(data: &IsrData) -> bool {
+    data.code_segment & 3 == 3
+}
+
+/// Ends user mode because of an exception, returning from [enter_user]
+///
+/// Must be called on the cpu the exception arrived on, after the interrupt
+/// frame is no longer needed.
+pub fn exit_on_exception(data: &IsrData) -> ! {
+    let vector = data.vector as u8;
+    exit(UserExit::Exception {
+        vector,
+        err_code: data.err_code,
+        rip: data.instruction as usize,
+        fault_addr: (vector == 14).then(Cr2::read),
+    })
+}
+
+/// Ends user mode, returning `reason` from [enter_user]
+pub(super) fn exit(reason: UserExit) -> ! {
+    // only called from interrupt handlers
+    let context = unsafe { CONTEXT.force() };
+    let saved_rsp = context.kernel_rsp.load(Ordering::Relaxed);
+    assert!(saved_rsp != 0, "not running user code");
+
+    *context.exit.lock() = Some(reason);
+    // gs already belongs to the kernel, isr.asm swapped it on entry
+    unsafe { user_return(saved_rsp) }
+}
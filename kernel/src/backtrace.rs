@@ -0,0 +1,130 @@
+use core::ops::Range;
+
+use crate::{arch, mem::HIGHER_HALF_ADDR, println, stack, symbols};
+
+/// Frames bigger than this are assumed to be a broken chain
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// Longest symbol name printed in a backtrace
+const MAX_NAME_LEN: usize = 256;
+/// Enough of a symbol name to compare it against a path
+const MAX_PATH_LEN: usize = 64;
+
+/// Fills `buf` with the return addresses of the current call stack,
+/// skipping the innermost `skip` frames
+///
+/// Returns the number of addresses written
+#[inline(never)]
+pub fn capture(skip: usize, buf: &mut [usize]) -> usize {
+    let frames = Frames::new(arch::frame_pointer()).skip(skip);
+    buf.iter_mut()
+        .zip(frames)
+        .map(|(slot, addr)| *slot = addr)
+        .count()
+}
+
+/// Fills `buf` with the return addresses of the current call stack, starting
+/// at the first frame whose function isn't under one of `paths`
+///
+/// Without a symbol table the functions can't be told apart, so the innermost
+/// `fallback_skip` frames of the caller are skipped instead. Returns the
+/// number of addresses written.
+#[inline(never)]
+pub fn capture_outside(paths: &[&str], fallback_skip: usize, buf: &mut [usize]) -> usize {
+    if !symbols::is_available() {
+        // and this function's own frame
+        return capture(fallback_skip + 1, buf);
+    }
+
+    let mut name = [0; MAX_PATH_LEN];
+    let frames = Frames::new(arch::frame_pointer()).skip_while(|&addr| {
+        symbols::lookup(addr - 1, &mut name).is_some_and(|symbol| {
+            // trait impls are named `<path as Trait>::method`
+            let name = symbol.name.trim_start_matches('<');
+            paths.iter().any(|path| name.starts_with(path))
+        })
+    });
+    buf.iter_mut()
+        .zip(frames)
+        .map(|(slot, addr)| *slot = addr)
+        .count()
+}
+
+/// Prints a symbolized backtrace, starting at `frame_pointer`
+pub fn print(frame_pointer: usize) {
+    let mut name = [0; MAX_NAME_LEN];
+    for addr in Frames::new(frame_pointer).take(32) {
+        // return addresses point after the call, which may be a different function
+        match symbols::lookup(addr - 1, &mut name) {
+            Some(symbol) => println!("\t{addr:#018x} {}+{:#x}", symbol.name, symbol.offset + 1),
+            None => println!("\t{addr:#018x}"),
+        }
+    }
+}
+
+/// Iterator over the return addresses of a frame pointer chain
+///
+/// Frames are kept within the kernel stack they're on. The chain may move
+/// to another kernel stack, like from an interrupt stack to the interrupted
+/// one, but only ever to a higher address within the same stack.
+pub struct Frames {
+    frame_pointer: usize,
+    /// Usable range of the stack the frame pointer is on, if it's known
+    stack: Option<Range<usize>>,
+}
+
+impl Frames {
+    /// `frame_pointer` must either be null, or point to a valid frame
+    pub fn new(frame_pointer: usize) -> Self {
+        Self {
+            frame_pointer,
+            stack: stack_of(frame_pointer),
+        }
+    }
+}
+
+/// Returns the usable part of the kernel stack an address is on
+fn stack_of(addr: usize) -> Option<Range<usize>> {
+    stack::find(addr)
+        .filter(|info| !info.is_guard(addr))
+        .map(|info| info.bottom..info.top)
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let fp = self.frame_pointer;
+        if fp < HIGHER_HALF_ADDR || fp % 16 != 0 {
+            return None;
+        }
+        if let Some(stack) = &self.stack {
+            // the frame is two words
+            if !(stack.start..stack.end - 8).contains(&fp) {
+                return None;
+            }
+        }
+
+        let next = unsafe { *(fp as *const usize) };
+        let ret = unsafe { *((fp + 8) as *const usize) };
+
+        let same_stack = next > fp
+            && match &self.stack {
+                Some(stack) => stack.contains(&next),
+                None => next - fp <= MAX_FRAME_SIZE,
+            };
+        self.frame_pointer = if same_stack {
+            next
+        } else if let Some(stack) = stack_of(next).filter(|stack| {
+            self.stack
+                .as_ref()
+                .is_some_and(|current| current.start != stack.start)
+        }) {
+            self.stack = Some(stack);
+            next
+        } else {
+            0
+        };
+
+        (ret != 0).then_some(ret)
+    }
+}
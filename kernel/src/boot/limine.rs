@@ -1,20 +1,23 @@
 use core::{arch::asm, ptr::addr_of};
 
+use alloc::{boxed::Box, vec::Vec};
+
 use limine::{
     memory_map::EntryType,
     request::{
         HhdmRequest, KernelAddressRequest, KernelFileRequest, MemoryMapRequest, RequestsEndMarker,
-        RequestsStartMarker, SmpRequest, StackSizeRequest,
+        RequestsStartMarker, RsdpRequest, SmpRequest, StackSizeRequest,
     },
     smp::Cpu,
     BaseRevision,
 };
+use spin::Once;
 
 use crate::{
     arch, kmain,
     mem::{MappingKind, PhysPtr},
     println,
-    stack::{Stack, STACK_SIZE},
+    stack::{Stack, StackSize, STACK_SIZE},
 };
 
 #[derive(Debug)]
@@ -49,6 +52,10 @@ static KERNEL_FILE_REQUEST: KernelFileRequest = KernelFileRequest::new();
 #[link_section = ".requests"]
 static SMP_REQUEST: SmpRequest = SmpRequest::new();
 
+#[used]
+#[link_section = ".requests"]
+static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+
 #[used]
 #[link_section = ".requests"]
 static STACK_SIZE_REQUEST: StackSizeRequest = StackSizeRequest::new().with_size(STACK_SIZE as u64);
@@ -73,34 +80,22 @@ fn verify_requests() {
 #[no_mangle]
 extern "C" fn entry() -> ! {
     extern "C" fn entry(cpu: &Cpu) -> ! {
-        let response = SMP_REQUEST.get_response().unwrap();
-        let bsp_lapic_id = response.bsp_lapic_id();
-        let cpus = response.cpus();
-
-        let cpuid = if cpu.lapic_id == bsp_lapic_id {
-            0
-        } else {
-            cpus.iter()
-                .filter(|c| c.lapic_id != bsp_lapic_id)
-                .enumerate()
-                .find(|(_, c)| c.lapic_id == cpu.lapic_id)
-                .unwrap()
-                .0
-                + 1
-        }
-        .try_into()
-        .unwrap();
+        let cpuid = assign_cpuid(cpu.lapic_id);
 
         arch::init(cpuid);
 
-        let new_sp = Stack::new()
-            .expect("critical mapping failed")
-            .stack_pointer();
+        // this cpu never leaves its boot stack
+        let stack = Box::leak(Box::new(
+            Stack::with_size(StackSize::Large).expect("critical mapping failed"),
+        ));
+        stack.set_owner("boot stack", Some(cpuid));
+        let new_sp = stack.stack_pointer();
 
         unsafe {
             asm!(
                 "
             mov rsp, {new_sp}
+            xor ebp, ebp
             mov rdi, {cpuid}
             call {kmain}
             ud2
@@ -142,6 +137,70 @@ pub fn cpu_count() -> u32 {
         .unwrap()
 }
 
+/// Local apic ids indexed by cpuid, see [assign_cpuid]
+static APIC_IDS: Once<Vec<u32>> = Once::new();
+
+/// Returns the cpuid of the cpu with the given local apic id
+///
+/// The bsp is cpu 0 and the others follow in order of apic id, so the mapping
+/// doesn't depend on the order the bootloader lists cpus in. This runs before
+/// the heap is mapped, so it counts instead of building [APIC_IDS].
+fn assign_cpuid(lapic_id: u32) -> u32 {
+    let response = SMP_REQUEST.get_response().unwrap();
+    let bsp_lapic_id = response.bsp_lapic_id();
+    if lapic_id == bsp_lapic_id {
+        return 0;
+    }
+
+    let lower = response
+        .cpus()
+        .iter()
+        .filter(|c| c.lapic_id != bsp_lapic_id && c.lapic_id < lapic_id)
+        .count();
+    1 + lower as u32
+}
+
+fn apic_ids() -> &'static [u32] {
+    APIC_IDS.call_once(|| {
+        let response = SMP_REQUEST.get_response().unwrap();
+        let bsp_lapic_id = response.bsp_lapic_id();
+
+        let mut ids: Vec<u32> = response
+            .cpus()
+            .iter()
+            .map(|c| c.lapic_id)
+            .filter(|&id| id != bsp_lapic_id)
+            .collect();
+        ids.sort_unstable();
+        ids.insert(0, bsp_lapic_id);
+        ids
+    })
+}
+
+/// Returns the local apic id of a cpu
+pub fn apic_id(cpuid: u32) -> u32 {
+    *apic_ids().get(cpuid as usize).expect("cpuid out of range")
+}
+
+/// Returns the cpuid of the cpu with the given local apic id, if there is one
+pub fn cpuid_of_apic(apic_id: u32) -> Option<u32> {
+    let ids = apic_ids();
+    if ids[0] == apic_id {
+        return Some(0);
+    }
+    let index = ids[1..].binary_search(&apic_id).ok()?;
+    Some(index as u32 + 1)
+}
+
+/// Returns the physical address of the acpi rsdp, if the firmware has one
+pub fn rsdp() -> Option<PhysPtr<()>> {
+    let addr = RSDP_REQUEST.get_response()?.address() as usize;
+
+    // older base revisions hand out an hhdm address instead
+    let hhdm = hhdm_offset() as usize;
+    Some(PhysPtr::new(if addr >= hhdm { addr - hhdm } else { addr }))
+}
+
 pub fn phys_memmap_usable() -> impl Iterator<Item = (PhysPtr<()>, usize)> {
     MEMORY_MAP_REQUEST
         .get_response()
@@ -165,6 +224,8 @@ pub fn virt_memmap() -> impl Iterator<Item = MemoryMapping> {
         static rodata_end: u8;
         static data_start: u8;
         static data_end: u8;
+        static ksyms_start: u8;
+        static ksyms_end: u8;
         static elf_end: u8;
     }
 
@@ -175,6 +236,8 @@ pub fn virt_memmap() -> impl Iterator<Item = MemoryMapping> {
     let rodata_end_addr = addr_of!(rodata_end) as usize;
     let data_start_addr = addr_of!(data_start) as usize;
     let data_end_addr = addr_of!(data_end) as usize;
+    let ksyms_start_addr = addr_of!(ksyms_start) as usize;
+    let ksyms_end_addr = addr_of!(ksyms_end) as usize;
     let _elf_end_addr = addr_of!(elf_end) as usize;
 
     let address = KERNEL_ADDRESS_REQUEST.get_response().unwrap();
@@ -201,6 +264,12 @@ pub fn virt_memmap() -> impl Iterator<Item = MemoryMapping> {
         phys: kernel_phys.byte_add(data_start_addr - elf_start_addr),
         size: data_end_addr - data_start_addr,
     };
+    let ksyms_mapping = MemoryMapping {
+        kind: MappingKind::ReadOnly,
+        virt: (ksyms_start_addr + kaslr_offset) as *const (),
+        phys: kernel_phys.byte_add(ksyms_start_addr - elf_start_addr),
+        size: ksyms_end_addr - ksyms_start_addr,
+    };
 
     MEMORY_MAP_REQUEST
         .get_response()
@@ -231,5 +300,5 @@ pub fn virt_memmap() -> impl Iterator<Item = MemoryMapping> {
                 _ => None,
             }
         })
-        .chain([text_mapping, rodata_mapping, data_mapping])
+        .chain([text_mapping, rodata_mapping, data_mapping, ksyms_mapping])
 }
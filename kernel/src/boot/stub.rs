@@ -31,6 +31,18 @@ pub fn cpu_count() -> u32 {
     CPUS
 }
 
+pub fn apic_id(cpuid: u32) -> u32 {
+    cpuid
+}
+
+pub fn cpuid_of_apic(apic_id: u32) -> Option<u32> {
+    (apic_id < CPUS).then_some(apic_id)
+}
+
+pub fn rsdp() -> Option<PhysPtr<()>> {
+    None
+}
+
 pub fn phys_memmap_usable() -> impl Iterator<Item = (PhysPtr<()>, usize)> {
     todo!();
     #[expect(unreachable_code)]
@@ -1,37 +1,161 @@
-use core::{cell::Cell, ops::Deref};
+//! Per cpu variables
+//!
+//! Every cpu has an area of its own, and each [CpuLocal] gets the same offset
+//! in all of them the first time it's used. The arch code keeps a pointer to
+//! the current cpu's area at hand, so finding a value never has to look up
+//! which cpu this is. Areas are cache line aligned, so values belonging to
+//! different cpus never share a line.
 
-use alloc::boxed::Box;
+use core::{
+    alloc::Layout,
+    cell::Cell,
+    marker::PhantomData,
+    ops::Deref,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::alloc::{alloc_zeroed, handle_alloc_error};
 use spin::Once;
 
-use crate::{arch, boot::cpu_count};
+use crate::{arch, boot::cpu_count, sync::InterruptGuard};
+
+/// Size of every cpu's area, which all the [CpuLocal]s have to fit in
+const AREA_SIZE: usize = 16 * 1024;
+const CACHE_LINE: usize = 64;
+
+struct Areas {
+    base: NonNull<u8>,
+    count: u32,
+}
+
+unsafe impl Send for Areas {}
+unsafe impl Sync for Areas {}
+
+static AREAS: Once<Areas> = Once::new();
+/// Start of the free space in every area, the arch code reserves the first bytes
+static NEXT_OFFSET: AtomicUsize = AtomicUsize::new(arch::PERCPU_RESERVED);
+
+fn areas() -> &'static Areas {
+    AREAS.call_once(|| {
+        let count = cpu_count();
+        let layout = Layout::from_size_align(AREA_SIZE * count as usize, CACHE_LINE).unwrap();
+        let base = unsafe { alloc_zeroed(layout) };
+        let base = NonNull::new(base).unwrap_or_else(|| handle_alloc_error(layout));
+        Areas { base, count }
+    })
+}
+
+/// Returns the start of a cpu's area
+pub fn area(cpuid: u32) -> NonNull<u8> {
+    let areas = areas();
+    assert!(cpuid < areas.count, "cpuid {cpuid} out of range");
+    unsafe { areas.base.add(cpuid as usize * AREA_SIZE) }
+}
+
+/// Returns true if `addr` is the start of some cpu's area
+///
+/// Never allocates the areas, so this is usable from anywhere.
+pub fn is_area(addr: usize) -> bool {
+    let Some(areas) = AREAS.get() else {
+        return false;
+    };
+    let offset = addr.wrapping_sub(areas.base.as_ptr() as usize);
+    offset < AREA_SIZE * areas.count as usize && offset % AREA_SIZE == 0
+}
 
+/// Reserves room for a value in every area, returns its offset
+fn allocate(layout: Layout) -> usize {
+    assert!(
+        layout.align() <= CACHE_LINE,
+        "cpu local values can't be aligned to more than a cache line"
+    );
+    let mut offset = 0;
+    NEXT_OFFSET
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+            offset = next.next_multiple_of(layout.align());
+            Some(offset + layout.size())
+        })
+        .unwrap();
+    assert!(
+        offset + layout.size() <= AREA_SIZE,
+        "out of space for cpu local values"
+    );
+    offset
+}
+
+/// A value every cpu has its own copy of
+///
+/// `init` creates the values for all cpus the first time any of them is used.
 pub struct CpuLocal<T, F = fn(u32) -> T> {
-    cell: Once<Box<[T]>>,
+    offset: Once<usize>,
     init: Cell<Option<F>>,
+    _values: PhantomData<T>,
 }
 
-unsafe impl<T, F: Send> Sync for CpuLocal<T, F> where Once<Box<[T]>>: Sync {}
+unsafe impl<T: Send + Sync, F: Send> Sync for CpuLocal<T, F> {}
 
 impl<T> CpuLocal<T> {
     pub const fn new(f: fn(u32) -> T) -> Self {
         Self {
-            cell: Once::new(),
+            offset: Once::new(),
             init: Cell::new(Some(f)),
+            _values: PhantomData,
         }
     }
 
-    pub fn force(&self) -> &T {
-        &self.cell.call_once(|| match self.init.take() {
-            Some(f) => (0..cpu_count()).map(f).collect(),
-            None => panic!("CpuLocal instance has previously been poisoned"),
-        })[arch::get_cpuid() as usize]
+    fn offset(&self) -> usize {
+        *self.offset.call_once(|| {
+            let Some(f) = self.init.take() else {
+                panic!("CpuLocal instance has previously been poisoned");
+            };
+            let offset = allocate(Layout::new::<T>());
+            for cpuid in 0..cpu_count() {
+                let value = unsafe { area(cpuid).add(offset).cast::<T>() };
+                unsafe { value.write(f(cpuid)) };
+            }
+            offset
+        })
+    }
+
+    /// Returns the current cpu's value without disabling interrupts
+    ///
+    /// # Safety
+    /// The caller must not move to another cpu while it holds the reference,
+    /// usually because interrupts are already disabled. Use [CpuLocal::local]
+    /// otherwise.
+    pub unsafe fn force(&self) -> &T {
+        let offset = self.offset();
+        unsafe { &*arch::this_cpu_area().add(offset).cast::<T>() }
     }
+
+    /// Returns the current cpu's value, with interrupts disabled until the
+    /// returned guard is dropped so nothing else runs on this cpu meanwhile
+    pub fn local(&self) -> Local<'_, T> {
+        let guard = InterruptGuard::new();
+        Local {
+            value: unsafe { self.force() },
+            _guard: guard,
+        }
+    }
+
+    /// Returns the value belonging to another cpu
+    pub fn get(&self, cpuid: u32) -> &T {
+        let offset = self.offset();
+        unsafe { area(cpuid).add(offset).cast::<T>().as_ref() }
+    }
+}
+
+/// The current cpu's value of a [CpuLocal], see [CpuLocal::local]
+pub struct Local<'a, T> {
+    value: &'a T,
+    _guard: InterruptGuard,
 }
 
-impl<T> Deref for CpuLocal<T> {
+impl<T> Deref for Local<'_, T> {
     type Target = T;
 
-    fn deref(&self) -> &Self::Target {
-        self.force()
+    fn deref(&self) -> &T {
+        self.value
     }
 }
@@ -0,0 +1,237 @@
+//! Sets of cpus, one bit per cpuid
+//!
+//! Every operation is atomic, so a set can be shared between cpus without a
+//! lock. Operations on different cpus of the same set don't synchronize with
+//! each other beyond that, a snapshot like [CpuSet::len] may be stale as soon
+//! as it returns.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::boxed::Box;
+
+use crate::boot::cpu_count;
+
+const BITS: u32 = u64::BITS;
+
+pub struct CpuSet {
+    words: Box<[AtomicU64]>,
+    capacity: u32,
+}
+
+impl CpuSet {
+    /// An empty set with room for every cpu
+    pub fn new() -> Self {
+        Self::with_capacity(cpu_count())
+    }
+
+    /// An empty set with room for cpuids below `capacity`
+    pub fn with_capacity(capacity: u32) -> Self {
+        let words = capacity.div_ceil(BITS) as usize;
+        Self {
+            words: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            capacity,
+        }
+    }
+
+    /// A set of every cpu
+    pub fn all() -> Self {
+        let set = Self::new();
+        set.fill();
+        set
+    }
+
+    /// Adds every cpuid below the capacity
+    fn fill(&self) {
+        for (index, word) in self.words.iter().enumerate() {
+            let remaining = self.capacity - index as u32 * BITS;
+            let bits = if remaining >= BITS {
+                u64::MAX
+            } else {
+                (1 << remaining) - 1
+            };
+            word.store(bits, Ordering::Release);
+        }
+    }
+
+    /// A set of only the given cpu
+    pub fn only(cpuid: u32) -> Self {
+        let set = Self::new();
+        set.insert(cpuid);
+        set
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn bit(&self, cpuid: u32) -> (&AtomicU64, u64) {
+        assert!(
+            cpuid < self.capacity,
+            "cpuid {cpuid} out of range for a set of {}",
+            self.capacity
+        );
+        (&self.words[(cpuid / BITS) as usize], 1 << (cpuid % BITS))
+    }
+
+    /// Adds a cpu, returns true if it wasn't in the set already
+    pub fn insert(&self, cpuid: u32) -> bool {
+        let (word, bit) = self.bit(cpuid);
+        word.fetch_or(bit, Ordering::AcqRel) & bit == 0
+    }
+
+    /// Removes a cpu, returns true if it was in the set
+    pub fn remove(&self, cpuid: u32) -> bool {
+        let (word, bit) = self.bit(cpuid);
+        word.fetch_and(!bit, Ordering::AcqRel) & bit != 0
+    }
+
+    pub fn contains(&self, cpuid: u32) -> bool {
+        let (word, bit) = self.bit(cpuid);
+        word.load(Ordering::Acquire) & bit != 0
+    }
+
+    pub fn len(&self) -> u32 {
+        self.words
+            .iter()
+            .map(|word| word.load(Ordering::Acquire).count_ones())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words
+            .iter()
+            .all(|word| word.load(Ordering::Acquire) == 0)
+    }
+
+    pub fn clear(&self) {
+        for word in self.words.iter() {
+            word.store(0, Ordering::Release);
+        }
+    }
+
+    /// Returns the lowest cpuid in the set
+    pub fn first(&self) -> Option<u32> {
+        self.iter().next()
+    }
+
+    /// Iterates over the cpuids in the set in increasing order
+    ///
+    /// Each word is read once when the iterator reaches it, so cpus added or
+    /// removed meanwhile may or may not be seen.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            words: &self.words,
+            index: 0,
+            current: self.words.first().map_or(0, |w| w.load(Ordering::Acquire)),
+        }
+    }
+}
+
+impl Default for CpuSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for CpuSet {
+    /// Takes a snapshot of the set
+    fn clone(&self) -> Self {
+        Self {
+            words: self
+                .words
+                .iter()
+                .map(|word| AtomicU64::new(word.load(Ordering::Acquire)))
+                .collect(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+impl fmt::Debug for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl FromIterator<u32> for CpuSet {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> Self {
+        let set = Self::new();
+        for cpuid in iter {
+            set.insert(cpuid);
+        }
+        set
+    }
+}
+
+impl<'a> IntoIterator for &'a CpuSet {
+    type Item = u32;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Cpuids in a [CpuSet], see [CpuSet::iter]
+pub struct Iter<'a> {
+    words: &'a [AtomicU64],
+    index: usize,
+    /// Bits of `words[index]` that haven't been returned yet
+    current: u64,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        while self.current == 0 {
+            self.index += 1;
+            self.current = self.words.get(self.index)?.load(Ordering::Acquire);
+        }
+        let bit = self.current.trailing_zeros();
+        self.current &= self.current - 1;
+        Some(self.index as u32 * BITS + bit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn fill_partial_word() {
+        let set = CpuSet::with_capacity(70);
+        set.fill();
+        assert_eq!(set.len(), 70);
+        assert!(set.contains(69));
+        assert_eq!(set.iter().collect::<Vec<_>>(), (0..70).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iter_skips_empty_words() {
+        let set = CpuSet::with_capacity(200);
+        assert_eq!(set.first(), None);
+
+        set.insert(150);
+        assert_eq!(set.iter().collect::<Vec<_>>(), [150]);
+        set.insert(1);
+        set.insert(199);
+        assert_eq!(set.iter().collect::<Vec<_>>(), [1, 150, 199]);
+    }
+
+    #[test]
+    fn remove_last() {
+        let set = CpuSet::with_capacity(130);
+        assert!(set.insert(129));
+        assert!(!set.insert(129));
+        assert!(set.remove(129));
+        assert!(!set.remove(129));
+        assert!(set.is_empty());
+        assert_eq!(set.first(), None);
+    }
+}
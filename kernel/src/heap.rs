@@ -1,42 +1,420 @@
-use spin::Mutex;
+#[cfg(feature = "heap-tracking")]
+mod tracking;
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ops::Range,
+    ptr,
+};
+
 use talc::{Span, Talc, Talck};
 
-use crate::mem::{Mapper, MappingKind, KERNEL_MAPPER, PAGE_SIZE};
+#[cfg(feature = "kasan")]
+use crate::kasan;
+use crate::{
+    arch,
+    mem::{
+        phys,
+        tlb::{self, Generation},
+        KernelMapper, Mapper, MappingError, MappingKind, Page, PhysPtr, KERNEL_MAPPER, PAGE_SIZE,
+    },
+    sync::{InterruptGuard, IrqSpinlock},
+};
 
 #[cfg_attr(target_os = "none", global_allocator)]
-static ALLOCATOR: Talck<spin::Mutex<()>, MyOomHandler> = Talc::new(MyOomHandler).lock();
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+/// Only locked with interrupts disabled, see [KernelAllocator]
+static TALC: Talck<spin::Mutex<()>, MyOomHandler> = Talc::new(MyOomHandler).lock();
+
+/// Virtual addresses the heap may grow into
+pub const REGION: Range<usize> = 0xffff_9000_0000_0000..0xffff_9000_0000_0000 + HEAP_MAX_SIZE;
+
+const HEAP_START: *mut u8 = REGION.start as *mut u8;
+static HEAP: IrqSpinlock<Heap> = IrqSpinlock::new(Heap::new());
+
+/// The heap is never grown past this size
+const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+/// The heap is never shrunk below this size
+const HEAP_MIN_SIZE: usize = 16 * PAGE_SIZE;
+/// Extra space reserved for talc's own bookkeeping when growing
+const HEAP_OVERHEAD: usize = 64;
+
+#[cfg(feature = "heap-tracking")]
+pub use tracking::report;
+
+/// Functions that run between the code allocating or freeing memory and the
+/// allocator, skipped when recording where that happened
+#[cfg(any(feature = "heap-tracking", feature = "kasan"))]
+pub const ALLOCATOR_PATHS: &[&str] = &[
+    "kernel::heap::",
+    "kernel::kasan::",
+    "alloc::",
+    "core::alloc::",
+    "core::ptr::drop_in_place",
+    "__rust_",
+    "__rg_",
+];
+
+/// Keeps interrupts disabled while it runs, since interrupt handlers allocate
+struct KernelAllocator;
+
+struct Heap {
+    /// The span claimed by [TALC]
+    span: Span,
+    /// End of the mapped part of [REGION], the span may end a little before it
+    end: *mut u8,
+    released: Released,
+}
+
+unsafe impl Send for Heap {}
+
+/// Frames unmapped from the end of the heap, which other cpus may still have
+/// in their tlbs
+///
+/// They're linked through their first bytes, in address order starting from
+/// [Heap::end]. Growing the heap maps them back where they were, so a stale
+/// tlb entry still points at the right frame. Once every cpu has flushed
+/// they're freed.
+struct Released {
+    first: Option<PhysPtr<Page>>,
+    /// When the most recent of them was unmapped
+    generation: Option<Generation>,
+}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            span: Span::empty(),
+            end: HEAP_START,
+            released: Released {
+                first: None,
+                generation: None,
+            },
+        }
+    }
+
+    /// Maps `size` more bytes at the end, reusing released frames first
+    unsafe fn map_more(
+        &mut self,
+        mapper: &mut KernelMapper,
+        size: usize,
+    ) -> Result<(), MappingError> {
+        let start = self.end;
+        let mut reused = 0;
+        let mut result = Ok(());
+
+        while reused < size {
+            let Some(frame) = self.released.pop() else {
+                break;
+            };
+            let ptr = start.wrapping_add(reused).cast();
+            result =
+                unsafe { mapper.map_phys(ptr, frame.cast(), PAGE_SIZE, MappingKind::ReadWrite) };
+            if result.is_err() {
+                self.released.push(frame);
+                break;
+            }
+            reused += PAGE_SIZE;
+        }
+        if result.is_ok() {
+            let ptr = start.wrapping_add(reused).cast();
+            result = unsafe { mapper.map(ptr, size - reused, MappingKind::ReadWrite) };
+        }
+
+        if result.is_err() {
+            // put the reused frames back in the same order
+            for offset in (0..reused).step_by(PAGE_SIZE).rev() {
+                let frame = unsafe { mapper.unmap_page(start.wrapping_add(offset).cast()) };
+                self.released.push(frame.expect("page was just mapped"));
+            }
+            return result;
+        }
+
+        self.end = start.wrapping_add(size);
+        Ok(())
+    }
+}
+
+impl Released {
+    fn push(&mut self, frame: PhysPtr<Page>) {
+        let next = frame.cast::<Option<PhysPtr<Page>>>();
+        unsafe { next.as_mut_ptr().write(self.first) };
+        self.first = Some(frame);
+    }
+
+    fn pop(&mut self) -> Option<PhysPtr<Page>> {
+        let frame = self.first?;
+        self.first = unsafe { frame.cast::<Option<PhysPtr<Page>>>().as_ptr().read() };
+        Some(frame)
+    }
+
+    /// Frees the frames once every cpu has flushed them
+    fn reclaim(&mut self) {
+        if !self.generation.is_some_and(tlb::is_flushed) {
+            return;
+        }
+        while let Some(frame) = self.pop() {
+            unsafe { phys::dealloc(frame) };
+        }
+        self.generation = None;
+    }
+}
+
+/// Metadata stored directly in front of every allocation
+///
+/// This is zero sized unless a debugging feature needs it
+#[repr(C)]
+struct AllocHeader {
+    // talc keeps its free list at the start of freed blocks,
+    // don't let it overwrite the kasan info
+    #[cfg(feature = "kasan")]
+    _free_list: [usize; 4],
+    #[cfg(feature = "heap-tracking")]
+    site: usize,
+    // must be last, kasan finds it by scanning backwards from the data
+    #[cfg(feature = "kasan")]
+    kasan: kasan::AllocInfo,
+}
+
+// only heap tracking needs the old size and layout
+#[cfg_attr(not(feature = "heap-tracking"), allow(unused_variables))]
+impl AllocHeader {
+    // without a symbol table, the sanitizer and heap tracking skip a fixed
+    // number of frames
+    #[inline(never)]
+    fn new(layout: Layout) -> Self {
+        Self {
+            #[cfg(feature = "kasan")]
+            _free_list: [0; 4],
+            #[cfg(feature = "heap-tracking")]
+            site: tracking::record(layout.size()),
+            #[cfg(feature = "kasan")]
+            kasan: kasan::AllocInfo::new(layout.size()),
+        }
+    }
+
+    fn resize(&mut self, old_size: usize, new_size: usize) {
+        #[cfg(feature = "heap-tracking")]
+        tracking::resize(self.site, old_size, new_size);
+        #[cfg(feature = "kasan")]
+        self.kasan.resize(new_size);
+    }
+
+    #[inline(never)]
+    fn free(&mut self, layout: Layout) {
+        #[cfg(feature = "heap-tracking")]
+        tracking::release(self.site, layout.size());
+        #[cfg(feature = "kasan")]
+        self.kasan.free();
+    }
+}
+
+/// Returns the layout of an allocation including its header,
+/// and the offset of the user data within it
+fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let (outer, offset) = Layout::new::<AllocHeader>().extend(layout).ok()?;
+    #[cfg(feature = "kasan")]
+    let (outer, _) = outer
+        .extend(Layout::new::<[u8; kasan::REDZONE_SIZE]>())
+        .ok()?;
+    Some((outer, offset))
+}
+
+/// Poisons the header and redzone of an allocation, leaving only the data accessible
+#[cfg(feature = "kasan")]
+fn poison_allocation(base: *mut u8, outer: Layout, ptr: *mut u8, size: usize) {
+    kasan::poison(base, outer.size(), kasan::HEAP_REDZONE);
+    kasan::unpoison(ptr, size);
+}
+
+/// Returns the currently mapped part of the heap, if it isn't locked
+#[cfg(feature = "kasan")]
+pub fn mapped_range() -> Option<(usize, usize)> {
+    let (base, acme) = HEAP.try_lock()?.span.get_base_acme()?;
+    Some((base as usize, acme as usize))
+}
+
+/// # Safety
+/// `ptr` must have been returned by [KernelAllocator]
+unsafe fn header_of(ptr: *mut u8) -> *mut AllocHeader {
+    // in bytes, the header is zero sized without debugging features
+    unsafe { ptr.byte_sub(size_of::<AllocHeader>()).cast() }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _interrupts = InterruptGuard::new();
+        #[cfg(feature = "kasan")]
+        let _guard = kasan::suppress();
+
+        let Some((outer, offset)) = outer_layout(layout) else {
+            return ptr::null_mut();
+        };
+
+        let base = unsafe { TALC.alloc(outer) };
+        if base.is_null() {
+            return base;
+        }
+
+        let ptr = unsafe { base.add(offset) };
+        unsafe { header_of(ptr).write(AllocHeader::new(layout)) };
+        #[cfg(feature = "kasan")]
+        poison_allocation(base, outer, ptr, layout.size());
+        ptr
+    }
+
+    #[inline(never)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _interrupts = InterruptGuard::new();
+        #[cfg(feature = "kasan")]
+        let _guard = kasan::suppress();
+
+        let (outer, offset) = outer_layout(layout).expect("layout was valid when allocated");
+        let base = unsafe { ptr.sub(offset) };
+
+        unsafe { (*header_of(ptr)).free(layout) };
+        // once talc has it, another cpu may allocate and unpoison it
+        #[cfg(feature = "kasan")]
+        kasan::poison(base, outer.size(), kasan::HEAP_FREE);
+        unsafe { TALC.dealloc(base, outer) };
+        shrink();
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _interrupts = InterruptGuard::new();
+        #[cfg(feature = "kasan")]
+        let _guard = kasan::suppress();
+
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let (outer, offset) = outer_layout(layout).expect("layout was valid when allocated");
+        let Some((new_outer, _)) = outer_layout(new_layout) else {
+            return ptr::null_mut();
+        };
+
+        // the header is copied along with the data
+        let base = unsafe { ptr.sub(offset) };
+        // the old block may move or shrink, and once talc has the freed part
+        // another cpu may allocate and unpoison it
+        #[cfg(feature = "kasan")]
+        kasan::poison(base, outer.size(), kasan::HEAP_FREE);
+        let new_base = unsafe { TALC.realloc(base, outer, new_outer.size()) };
+        if new_base.is_null() {
+            #[cfg(feature = "kasan")]
+            poison_allocation(base, outer, ptr, layout.size());
+            return new_base;
+        }
 
-const HEAP_START: *mut u8 = 0xffff_9000_0000_0000 as *mut u8;
-static HEAP_SPAN: Mutex<Span> = Mutex::new(Span::empty());
+        let new_ptr = unsafe { new_base.add(offset) };
+        unsafe { (*header_of(new_ptr)).resize(layout.size(), new_size) };
+        #[cfg(feature = "kasan")]
+        poison_allocation(new_base, new_outer, new_ptr, new_size);
+        new_ptr
+    }
+}
+
+/// Releases free pages at the end of the heap once at least half of it is unused
+///
+/// Other cpus may still have the pages in their tlbs, so the frames are only
+/// freed once all of them have flushed, see [Released].
+fn shrink() {
+    let mut talc = TALC.lock();
+    let mut heap = HEAP.lock();
+    heap.released.reclaim();
+
+    let Some((base, _)) = heap.span.get_base_acme() else {
+        return;
+    };
+    let size = heap.end as usize - base as usize;
+
+    // SAFETY: heap.span is the span claimed by TALC
+    let used = match unsafe { talc.get_allocated_span(heap.span) }.get_base_acme() {
+        Some((_, used_acme)) => used_acme as usize - base as usize,
+        None => 0,
+    };
+    let keep = used.max(HEAP_MIN_SIZE).next_multiple_of(PAGE_SIZE);
+
+    if keep >= size || size - keep < size / 2 {
+        return;
+    }
+
+    // the other cpus' tlbs aren't tracked before this cpu's percpu data is ready
+    if arch::try_get_cpuid().is_none() {
+        return;
+    }
+
+    // freeing memory while the mapper is held is fine, just don't shrink
+    let Some(mut mapper) = KERNEL_MAPPER.try_lock() else {
+        return;
+    };
+
+    heap.span = unsafe { talc.truncate(heap.span, Span::from_base_size(base, keep)) };
+
+    // talc may keep a little more than requested
+    let new_acme = heap.span.get_base_acme().map_or(base, |(_, acme)| acme);
+    let free_start = (new_acme as usize).next_multiple_of(PAGE_SIZE);
+
+    // from the top down, so the list starts at the new end
+    for vaddr in (free_start..heap.end as usize).step_by(PAGE_SIZE).rev() {
+        let frame = unsafe { mapper.unmap_page(vaddr as *mut ()) };
+        heap.released.push(frame.expect("heap should be mapped"));
+    }
+    heap.end = free_start as *mut u8;
+    heap.released.generation = Some(tlb::shootdown());
+}
 
 struct MyOomHandler;
 
 impl talc::OomHandler for MyOomHandler {
-    fn handle_oom(talc: &mut Talc<Self>, _layout: core::alloc::Layout) -> Result<(), ()> {
-        let mut heap_span = HEAP_SPAN.try_lock().expect("lock should always work");
-
-        if let Some((_base, acme)) = heap_span.get_base_acme() {
-            // add one more page
-            unsafe {
-                KERNEL_MAPPER
-                    .lock()
-                    .map(acme.cast(), PAGE_SIZE, MappingKind::ReadWrite)
+    fn handle_oom(talc: &mut Talc<Self>, layout: Layout) -> Result<(), ()> {
+        let mut heap = HEAP.try_lock().expect("lock should always work");
+        heap.released.reclaim();
+
+        // enough for the allocation, even if talc has to align it
+        let needed = (layout.size() + layout.align() + HEAP_OVERHEAD).next_multiple_of(PAGE_SIZE);
+        let start = heap.end;
+
+        if let Some((base, _)) = heap.span.get_base_acme() {
+            let size = heap.end as usize - base as usize;
+
+            // double the heap, unless the allocation needs even more
+            let mut grow = needed.max(size).min(HEAP_MAX_SIZE - size);
+            if grow < needed {
+                return Err(());
             }
-            .map_err(|_| ())?;
-            let old_span = *heap_span;
-            let new_span = heap_span.extend(0, PAGE_SIZE);
-            *heap_span = unsafe { talc.extend(old_span, new_span) };
+
+            let mut mapper = KERNEL_MAPPER.lock();
+            if unsafe { heap.map_more(&mut mapper, grow) }.is_err() {
+                // there may still be room for just the allocation
+                grow = needed;
+                unsafe { heap.map_more(&mut mapper, grow) }.map_err(|_| ())?;
+            }
+            drop(mapper);
+            #[cfg(feature = "kasan")]
+            poison_new_heap(start, grow);
+            let new_span = Span::new(base, heap.end);
+            heap.span = unsafe { talc.extend(heap.span, new_span) };
             Ok(())
         } else {
             // init heap
-            unsafe {
-                KERNEL_MAPPER
-                    .lock()
-                    .map(HEAP_START.cast(), PAGE_SIZE, MappingKind::ReadWrite)
+            let size = needed.max(HEAP_MIN_SIZE);
+            if size > HEAP_MAX_SIZE {
+                return Err(());
             }
-            .map_err(|_| ())?;
-            *heap_span = unsafe { talc.claim(Span::from_base_size(HEAP_START, PAGE_SIZE)) }?;
+
+            unsafe { heap.map_more(&mut KERNEL_MAPPER.lock(), size) }.map_err(|_| ())?;
+            #[cfg(feature = "kasan")]
+            poison_new_heap(start, size);
+            heap.span = unsafe { talc.claim(Span::from_base_size(start, size)) }?;
             Ok(())
         }
     }
 }
+
+/// Newly mapped heap memory is free until talc hands it out
+#[cfg(feature = "kasan")]
+fn poison_new_heap(ptr: *mut u8, size: usize) {
+    kasan::map_shadow(ptr.cast(), size);
+    kasan::poison(ptr, size, kasan::HEAP_FREE);
+}
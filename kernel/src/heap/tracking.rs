@@ -0,0 +1,137 @@
+use core::cmp::Reverse;
+
+use crate::{backtrace, println, sync::IrqSpinlock};
+
+/// Number of return addresses used to identify an allocation site
+const SITE_DEPTH: usize = 4;
+/// Number of allocation sites tracked individually, the rest share one entry
+const MAX_SITES: usize = 256;
+/// Site index for allocations that didn't fit in the table
+const OVERFLOW_SITE: usize = MAX_SITES;
+/// Frames belonging to the allocator itself when there is no symbol table to
+/// find them by: `record`, `AllocHeader::new` and `KernelAllocator::alloc`,
+/// none of which may be inlined
+const SKIP_FRAMES: usize = 3;
+
+#[derive(Clone, Copy)]
+struct Site {
+    frames: [usize; SITE_DEPTH],
+    live_bytes: usize,
+    live_count: usize,
+    peak_bytes: usize,
+    total_count: usize,
+}
+
+struct Tracker {
+    sites: [Site; MAX_SITES + 1],
+    live_bytes: usize,
+    peak_bytes: usize,
+}
+
+static TRACKER: IrqSpinlock<Tracker> = IrqSpinlock::new(Tracker {
+    sites: [Site::EMPTY; MAX_SITES + 1],
+    live_bytes: 0,
+    peak_bytes: 0,
+});
+
+impl Site {
+    const EMPTY: Self = Self {
+        frames: [0; SITE_DEPTH],
+        live_bytes: 0,
+        live_count: 0,
+        peak_bytes: 0,
+        total_count: 0,
+    };
+
+    fn is_empty(&self) -> bool {
+        self.total_count == 0
+    }
+
+    fn add(&mut self, size: usize) {
+        self.live_bytes += size;
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+    }
+}
+
+impl Tracker {
+    /// Finds the index of a site, inserting it if needed
+    fn find_or_insert(&mut self, frames: [usize; SITE_DEPTH]) -> usize {
+        let hash = frames.iter().fold(0usize, |acc, &x| {
+            acc.rotate_left(5) ^ x.wrapping_mul(0x9e37_79b9)
+        });
+
+        for i in 0..MAX_SITES {
+            let index = hash.wrapping_add(i) % MAX_SITES;
+            let site = &mut self.sites[index];
+            if site.is_empty() {
+                site.frames = frames;
+                return index;
+            }
+            if site.frames == frames {
+                return index;
+            }
+        }
+
+        OVERFLOW_SITE
+    }
+
+    fn add(&mut self, site: usize, size: usize) {
+        self.sites[site].add(size);
+        self.live_bytes += size;
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+    }
+
+    fn remove(&mut self, site: usize, size: usize) {
+        self.sites[site].live_bytes -= size;
+        self.live_bytes -= size;
+    }
+}
+
+/// Records a new allocation made by the caller, returning its site
+#[inline(never)]
+pub fn record(size: usize) -> usize {
+    let mut frames = [0; SITE_DEPTH];
+    backtrace::capture_outside(super::ALLOCATOR_PATHS, SKIP_FRAMES, &mut frames);
+
+    let mut tracker = TRACKER.lock();
+    let site = tracker.find_or_insert(frames);
+    tracker.add(site, size);
+    tracker.sites[site].live_count += 1;
+    tracker.sites[site].total_count += 1;
+    site
+}
+
+pub fn resize(site: usize, old_size: usize, new_size: usize) {
+    let mut tracker = TRACKER.lock();
+    tracker.remove(site, old_size);
+    tracker.add(site, new_size);
+}
+
+pub fn release(site: usize, size: usize) {
+    let mut tracker = TRACKER.lock();
+    tracker.remove(site, size);
+    tracker.sites[site].live_count -= 1;
+}
+
+/// Prints every allocation site that has been used, sorted by live bytes
+pub fn report() {
+    // this may run during a panic, so never wait for the lock
+    let Some(tracker) = TRACKER.try_lock() else {
+        println!("heap report unavailable: tracker is locked");
+        return;
+    };
+    let mut sites = tracker.sites;
+    let live_bytes = tracker.live_bytes;
+    let peak_bytes = tracker.peak_bytes;
+    drop(tracker);
+
+    sites.sort_unstable_by_key(|site| Reverse(site.live_bytes));
+
+    println!("heap: {live_bytes} bytes live, {peak_bytes} bytes peak");
+    for site in sites.iter().filter(|site| !site.is_empty()) {
+        println!(
+            "{:>10} bytes in {:>6} allocations (peak {}, total {}) at {:#x?}",
+            site.live_bytes, site.live_count, site.peak_bytes, site.total_count, site.frames,
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! Kernel address sanitizer
+//!
+//! Every 8 byte granule of tracked memory (the heap and kernel stacks) has one
+//! shadow byte: 0 means the whole granule is accessible, 1..=7 means only that
+//! many leading bytes are, and anything negative means the granule is poisoned.
+//!
+//! The compiler is told to outline every check (see the makefile), so only the
+//! callbacks at the bottom of this file ever read shadow memory.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{
+    cpulocal::{CpuLocal, Local},
+    heap,
+    mem::{Mapper, MappingError, MappingKind, KERNEL_MAPPER, PAGE_SIZE},
+    println, stack,
+};
+
+/// Same offset as linux, so inline instrumentation would also work
+const SHADOW_OFFSET: usize = 0xdfff_fc00_0000_0000;
+const SHADOW_SCALE: usize = 3;
+const GRANULE: usize = 1 << SHADOW_SCALE;
+
+/// Bytes of poisoned memory placed after every heap allocation
+pub const REDZONE_SIZE: usize = 2 * GRANULE;
+
+/// Heap memory around an allocation
+pub const HEAP_REDZONE: u8 = 0xfa;
+/// Heap memory that isn't allocated
+pub const HEAP_FREE: u8 = 0xfb;
+/// Guard pages around a kernel stack
+pub const STACK_GUARD: u8 = 0xf1;
+
+/// Number of return addresses recorded for allocation and free sites
+const TRACE_DEPTH: usize = 4;
+/// Frames belonging to the allocator itself when there is no symbol table to
+/// find them by: `AllocInfo::new` or `free`, then `AllocHeader::new` or
+/// `free` and the `KernelAllocator` method
+const SKIP_FRAMES: usize = 3;
+/// How far back from a bad access to look for its allocation
+const MAX_SCAN: usize = 1024 * 1024;
+const ALLOC_MAGIC: u64 = u64::from_le_bytes(*b"!kasan!!");
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SUPPRESS: CpuLocal<AtomicUsize> = CpuLocal::new(|_| AtomicUsize::new(0));
+
+/// Stored directly in front of every heap allocation
+#[repr(C)]
+pub struct AllocInfo {
+    magic: u64,
+    size: usize,
+    alloc_site: [usize; TRACE_DEPTH],
+    free_site: [usize; TRACE_DEPTH],
+}
+
+impl AllocInfo {
+    #[inline(never)]
+    pub fn new(size: usize) -> Self {
+        let mut alloc_site = [0; TRACE_DEPTH];
+        crate::backtrace::capture_outside(heap::ALLOCATOR_PATHS, SKIP_FRAMES, &mut alloc_site);
+
+        Self {
+            magic: ALLOC_MAGIC,
+            size,
+            alloc_site,
+            free_site: [0; TRACE_DEPTH],
+        }
+    }
+
+    #[inline(never)]
+    pub fn free(&mut self) {
+        crate::backtrace::capture_outside(heap::ALLOCATOR_PATHS, SKIP_FRAMES, &mut self.free_site);
+    }
+
+    pub fn resize(&mut self, new_size: usize) {
+        self.size = new_size;
+    }
+}
+
+/// Turns on checking, must only be called once every cpu has initialized
+/// its percpu data
+pub fn init() {
+    crate::assert_once!();
+
+    // force the allocation now, while nothing is being checked
+    SUPPRESS.get(0);
+    ENABLED.store(true, Ordering::Release);
+    println!("kasan enabled");
+}
+
+/// Disables reports on this cpu while the guard is alive
+///
+/// Interrupts stay disabled meanwhile, so the guard can't move to another cpu.
+pub struct SuppressGuard(Option<Local<'static, AtomicUsize>>);
+
+pub fn suppress() -> SuppressGuard {
+    if !ENABLED.load(Ordering::Acquire) {
+        return SuppressGuard(None);
+    }
+
+    let depth = SUPPRESS.local();
+    depth.fetch_add(1, Ordering::Relaxed);
+    SuppressGuard(Some(depth))
+}
+
+impl Drop for SuppressGuard {
+    fn drop(&mut self) {
+        if let Some(depth) = &self.0 {
+            depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn shadow_of(addr: usize) -> *mut i8 {
+    ((addr >> SHADOW_SCALE).wrapping_add(SHADOW_OFFSET)) as *mut i8
+}
+
+fn is_tracked(addr: usize) -> bool {
+    heap::REGION.contains(&addr) || stack::REGION.contains(&addr)
+}
+
+/// Maps the shadow memory for an address range, new shadow is unpoisoned
+pub fn map_shadow(ptr: *const (), size: usize) {
+    let start = shadow_of(ptr as usize) as usize / PAGE_SIZE * PAGE_SIZE;
+    let end = (shadow_of(ptr as usize + size - 1) as usize + 1).next_multiple_of(PAGE_SIZE);
+
+    let mut mapper = KERNEL_MAPPER.lock();
+    for page in (start..end).step_by(PAGE_SIZE) {
+        // neighbouring ranges may share shadow pages
+        match unsafe { mapper.map_zeroed(page as *mut (), PAGE_SIZE, MappingKind::ReadWrite) } {
+            Ok(()) | Err(MappingError::AlreadyMapped) => {}
+            Err(err) => panic!("failed to map kasan shadow: {err:?}"),
+        }
+    }
+}
+
+/// Marks `size` bytes at `ptr` as inaccessible
+///
+/// `ptr` must be aligned to 8 bytes, `size` is rounded up
+#[no_sanitize(address)]
+pub fn poison(ptr: *const u8, size: usize, value: u8) {
+    let addr = ptr as usize;
+    assert!(addr % GRANULE == 0, "ptr is misaligned");
+
+    for granule in (addr..addr + size).step_by(GRANULE) {
+        unsafe { shadow_of(granule).write(value as i8) };
+    }
+}
+
+/// Marks `size` bytes at `ptr` as accessible
+///
+/// `ptr` must be aligned to 8 bytes
+#[no_sanitize(address)]
+pub fn unpoison(ptr: *const u8, size: usize) {
+    let addr = ptr as usize;
+    assert!(addr % GRANULE == 0, "ptr is misaligned");
+
+    for granule in (addr..addr + size).step_by(GRANULE) {
+        let accessible = (addr + size - granule).min(GRANULE);
+        let value = if accessible == GRANULE { 0 } else { accessible };
+        unsafe { shadow_of(granule).write(value as i8) };
+    }
+}
+
+/// Returns the first inaccessible byte in the range, if any
+#[no_sanitize(address)]
+fn first_poisoned(addr: usize, size: usize) -> Option<usize> {
+    let end = addr + size;
+    let mut addr = addr;
+
+    while addr < end {
+        let granule = addr & !(GRANULE - 1);
+        let last = end.min(granule + GRANULE) - 1;
+        let shadow = unsafe { shadow_of(addr).read() };
+
+        if shadow < 0 {
+            return Some(addr);
+        }
+        if shadow > 0 && last - granule >= shadow as usize {
+            return Some(addr.max(granule + shadow as usize));
+        }
+
+        addr = granule + GRANULE;
+    }
+
+    None
+}
+
+/// Only ever inlined into the callbacks, which aren't instrumented
+#[inline(always)]
+fn access(addr: usize, size: usize, write: bool) {
+    if size == 0 || !is_tracked(addr) || !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let Some(bad) = first_poisoned(addr, size) else {
+        return;
+    };
+
+    // only consulted once something is wrong, since this touches tracked memory
+    if SUPPRESS.local().load(Ordering::Relaxed) != 0 {
+        return;
+    }
+
+    report(bad, addr, size, write);
+}
+
+#[inline(never)]
+#[no_sanitize(address)]
+fn report(bad: usize, addr: usize, size: usize, write: bool) -> ! {
+    // printing reads the poisoned allocation info
+    let _guard = suppress();
+
+    // skip report and the callback
+    let mut trace = [0; TRACE_DEPTH];
+    crate::backtrace::capture(2, &mut trace);
+
+    let shadow = unsafe { shadow_of(bad).read() } as u8;
+    let kind = match shadow {
+        HEAP_REDZONE => "heap out of bounds",
+        HEAP_FREE => "heap use after free",
+        STACK_GUARD => "stack guard",
+        1..=7 => "heap out of bounds",
+        _ => "unknown",
+    };
+
+    println!(
+        "kasan: {kind} {} of {size} bytes at {addr:#x} (first bad byte {bad:#x})",
+        if write { "write" } else { "read" },
+    );
+    println!("\taccessed at {trace:#x?}");
+
+    if let Some((ptr, info)) = find_allocation(bad) {
+        println!("\tallocation of {} bytes at {ptr:#x}", info.size);
+        println!("\tallocated at {:#x?}", info.alloc_site);
+        if info.free_site != [0; TRACE_DEPTH] {
+            println!("\tfreed at {:#x?}", info.free_site);
+        }
+    }
+
+    panic!("kasan: {kind}");
+}
+
+/// Best effort search for the heap allocation owning an address
+#[no_sanitize(address)]
+fn find_allocation(addr: usize) -> Option<(usize, &'static AllocInfo)> {
+    let (start, end) = heap::mapped_range()?;
+    if !(start..end).contains(&addr) {
+        return None;
+    }
+
+    let lowest = addr.saturating_sub(MAX_SCAN).max(start);
+    let mut candidate = addr & !(align_of::<AllocInfo>() - 1);
+
+    while candidate >= lowest && candidate + size_of::<AllocInfo>() <= end {
+        let info = unsafe { &*(candidate as *const AllocInfo) };
+        let ptr = candidate + size_of::<AllocInfo>();
+
+        if info.magic == ALLOC_MAGIC && addr < ptr + info.size + REDZONE_SIZE {
+            return Some((ptr, info));
+        }
+
+        candidate -= align_of::<AllocInfo>();
+    }
+
+    None
+}
+
+macro_rules! sized_callbacks {
+    ($($name:ident: $size:literal, $write:literal;)*) => {$(
+        #[no_mangle]
+        #[no_sanitize(address)]
+        extern "C" fn $name(addr: usize) {
+            access(addr, $size, $write);
+        }
+    )*};
+}
+
+macro_rules! unsized_callbacks {
+    ($($name:ident: $write:literal;)*) => {$(
+        #[no_mangle]
+        #[no_sanitize(address)]
+        extern "C" fn $name(addr: usize, size: usize) {
+            access(addr, size, $write);
+        }
+    )*};
+}
+
+sized_callbacks! {
+    __asan_load1: 1, false;
+    __asan_load2: 2, false;
+    __asan_load4: 4, false;
+    __asan_load8: 8, false;
+    __asan_load16: 16, false;
+    __asan_store1: 1, true;
+    __asan_store2: 2, true;
+    __asan_store4: 4, true;
+    __asan_store8: 8, true;
+    __asan_store16: 16, true;
+    __asan_load1_noabort: 1, false;
+    __asan_load2_noabort: 2, false;
+    __asan_load4_noabort: 4, false;
+    __asan_load8_noabort: 8, false;
+    __asan_load16_noabort: 16, false;
+    __asan_store1_noabort: 1, true;
+    __asan_store2_noabort: 2, true;
+    __asan_store4_noabort: 4, true;
+    __asan_store8_noabort: 8, true;
+    __asan_store16_noabort: 16, true;
+}
+
+unsized_callbacks! {
+    __asan_loadN: false;
+    __asan_storeN: true;
+    __asan_loadN_noabort: false;
+    __asan_storeN_noabort: true;
+}
+
+#[no_mangle]
+extern "C" fn __asan_handle_no_return() {}
+
+#[no_mangle]
+extern "C" fn __asan_register_globals(_globals: *const (), _count: usize) {}
+
+#[no_mangle]
+extern "C" fn __asan_unregister_globals(_globals: *const (), _count: usize) {}
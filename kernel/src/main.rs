@@ -1,33 +1,96 @@
 #![cfg_attr(target_os = "none", no_std)]
 #![cfg_attr(target_os = "none", no_main)]
 #![feature(allocator_api)]
+// later nightlies replace this with `#[sanitize(address = "off")]`, kasan needs
+// the toolchain pinned in rust-toolchain.toml
+#![cfg_attr(feature = "kasan", feature(no_sanitize))]
 #![allow(dead_code)]
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod arch;
+pub mod backtrace;
 pub mod boot;
 pub mod cpulocal;
+pub mod cpuset;
 pub mod framebuffer;
 pub mod heap;
+#[cfg(feature = "kasan")]
+pub mod kasan;
 pub mod mem;
 pub mod print;
 pub mod stack;
+pub mod symbols;
+pub mod sync;
+pub mod time;
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 use cpulocal::CpuLocal;
 
 #[no_mangle]
 extern "C" fn kmain() -> ! {
-    println!("I'm cpu {}", *CPUID);
+    let cpuid = *CPUID.local();
+    println!("I'm cpu {cpuid}");
+
+    if cpuid == 0 {
+        time::init();
+    }
+
+    // every cpu has initialized its percpu data by now
+    #[cfg(feature = "kasan")]
+    if cpuid == 0 {
+        kasan::init();
+    }
+
     println!("goodbye");
     arch::hcf();
 }
 
 static CPUID: CpuLocal<u32> = CpuLocal::new(|id| id);
 
+/// Cpu that is reporting a panic, [NO_CPU] if none is
+static PANIC_CPU: AtomicU32 = AtomicU32::new(NO_CPU);
+static NESTED_PANIC: AtomicBool = AtomicBool::new(false);
+const NO_CPU: u32 = u32::MAX;
+/// Used before percpu data is ready
+const UNKNOWN_CPU: u32 = u32::MAX - 1;
+
 #[cfg_attr(target_os = "none", panic_handler)]
 fn _rust_panic(info: &core::panic::PanicInfo) -> ! {
+    let cpuid = arch::try_get_cpuid();
+    let id = cpuid.unwrap_or(UNKNOWN_CPU);
+
+    if let Err(owner) = PANIC_CPU.compare_exchange(NO_CPU, id, Ordering::AcqRel, Ordering::Acquire)
+    {
+        // panicking while reporting a panic, only try to print once more. before
+        // percpu data is ready every cpu has the same id, so it can't be told
+        // apart from another cpu panicking
+        if cpuid.is_some() && owner == id && !NESTED_PANIC.swap(true, Ordering::AcqRel) {
+            unsafe { print::force_unlock() };
+            println!("nested panic: {info}");
+        }
+        // otherwise another cpu is reporting, and will stop this one
+        arch::hcf();
+    }
+
+    arch::stop_other_cpus();
+    // the other cpus are halted, whoever held the console won't release it
+    unsafe { print::force_unlock() };
+
+    match cpuid {
+        Some(cpuid) => println!("kernel panic on cpu {cpuid}"),
+        None => println!("kernel panic"),
+    }
     println!("{info}");
+    println!("registers:");
+    arch::print_registers();
+    println!("backtrace:");
+    backtrace::print(arch::frame_pointer());
+
+    #[cfg(feature = "heap-tracking")]
+    heap::report();
     arch::hcf();
 }
 
@@ -54,16 +117,14 @@ macro_rules! assert_once {
 #[macro_export]
 macro_rules! assert_once_percpu {
     () => {
-        assert_once_percpu!($crate::cpulocal::get_percpu().cpuid)
+        $crate::assert_once_percpu!($crate::arch::get_cpuid())
     };
     ($cpuid: expr) => {
         let cpuid: u32 = ($cpuid);
-        static CALLED: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
-        if cpuid < 64 {
-            let prev = CALLED.fetch_and(1 << cpuid, core::sync::atomic::Ordering::AcqRel);
-            if prev & (1 << cpuid) != 0 {
-                panic!("this function may only be called once per cpu")
-            }
+        static CALLED: ::spin::Lazy<$crate::cpuset::CpuSet> =
+            ::spin::Lazy::new($crate::cpuset::CpuSet::new);
+        if !CALLED.insert(cpuid) {
+            panic!("this function may only be called once per cpu")
         }
     };
 }
@@ -0,0 +1,284 @@
+use core::alloc::AllocError;
+
+use alloc::vec::Vec;
+
+use crate::{arch, cpuset::CpuSet};
+
+use super::{
+    mapping::{DEFAULT_ENTRIES, SPECIAL_GAURD},
+    phys,
+    x86_64::{self, PageTable, PageTableFlags, PageTableValue},
+    Mapper, MappingError, MappingKind, Page, PhysPtr, HIGHER_HALF_ADDR, PAGE_SIZE,
+};
+
+/// End of the lower half, user mappings live below it
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+/// Page tables of a user program
+///
+/// The upper half is shared with the kernel, the lower half holds user mappings
+/// accessible from ring 3. Dropping it frees every table and every frame
+/// allocated by [Mapper::map] or [Mapper::map_zeroed].
+pub struct AddressSpace {
+    ptroot: &'static PageTable,
+    phys: PhysPtr<PageTable>,
+    /// Cpus that have these tables loaded, see [AddressSpace::mark_loaded]
+    loaded: CpuSet,
+}
+
+unsafe impl Send for AddressSpace {}
+
+/// Flags for the tables leading to a user mapping
+fn table_flags() -> PageTableFlags {
+    PageTableFlags::from_kind(MappingKind::Full) | PageTableFlags::USER_ACCESSIBLE
+}
+
+fn check_range(vaddr: usize, size: usize) {
+    assert!(vaddr % PAGE_SIZE == 0, "ptr is misaligned");
+    assert!(size % PAGE_SIZE == 0, "size is misaligned");
+    assert!(
+        vaddr.checked_add(size).is_some_and(|end| end <= USER_END),
+        "range is not in lower half"
+    );
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, AllocError> {
+        let phys = PageTable::new()?;
+        let ptroot = unsafe { phys.as_nonnull().as_ref() };
+
+        for i in 0..256 {
+            ptroot.entries[i + 256]
+                .set(DEFAULT_ENTRIES.0[i])
+                .expect("ptroot should be empty");
+        }
+
+        Ok(Self {
+            ptroot,
+            phys,
+            loaded: CpuSet::new(),
+        })
+    }
+
+    /// Records that a cpu switched to these tables, so unmapping flushes its
+    /// tlb as well
+    pub fn mark_loaded(&self, cpuid: u32) {
+        self.loaded.insert(cpuid);
+    }
+
+    /// Records that a cpu switched away from these tables
+    pub fn mark_unloaded(&self, cpuid: u32) {
+        self.loaded.remove(cpuid);
+    }
+
+    /// Flushes a range from the tlbs of the other cpus that have these tables
+    /// loaded, and waits for them
+    fn shootdown(&self, vaddr: usize, size: usize) {
+        let others = self.loaded.clone();
+        if let Some(cpuid) = arch::try_get_cpuid() {
+            others.remove(cpuid);
+        }
+        if others.is_empty() {
+            return;
+        }
+
+        arch::run_on_set(&others, move || {
+            for page in (vaddr..vaddr + size).step_by(PAGE_SIZE) {
+                x86_64::flush_tlb(page);
+            }
+        });
+    }
+
+    /// Shared by [Mapper::map] and [Mapper::map_zeroed]
+    unsafe fn map_alloc(
+        &mut self,
+        ptr: *mut (),
+        size: usize,
+        kind: MappingKind,
+        alloc: fn() -> Result<PhysPtr<Page>, AllocError>,
+    ) -> Result<(), MappingError> {
+        let vaddr = ptr as usize;
+        check_range(vaddr, size);
+
+        for i in (0..size).step_by(PAGE_SIZE) {
+            let pte = x86_64::find_pte_or_create_with(self.ptroot, vaddr + i, table_flags());
+            pte.map_err(|e| {
+                unsafe { self.unmap_free(ptr, i) }
+                MappingError::AllocError(e)
+            })?
+            .set(match kind {
+                MappingKind::Gaurd => PageTableValue::Special(SPECIAL_GAURD),
+                _ => PageTableValue::Mapping {
+                    phys: alloc().map_err(|e| {
+                        unsafe { self.unmap_free(ptr, i) }
+                        MappingError::AllocError(e)
+                    })?,
+                    flags: PageTableFlags::from_kind(kind)
+                        | PageTableFlags::USER_ACCESSIBLE
+                        | PageTableFlags::OWNED,
+                },
+            })
+            .map_err(|_| {
+                unsafe { self.unmap_free(ptr, i) }
+                MappingError::AlreadyMapped
+            })?
+        }
+
+        Ok(())
+    }
+}
+
+impl Mapper for AddressSpace {
+    unsafe fn map(
+        &mut self,
+        ptr: *mut (),
+        size: usize,
+        kind: MappingKind,
+    ) -> Result<(), MappingError> {
+        unsafe { self.map_alloc(ptr, size, kind, phys::alloc) }
+    }
+
+    unsafe fn map_zeroed(
+        &mut self,
+        ptr: *mut (),
+        size: usize,
+        kind: MappingKind,
+    ) -> Result<(), MappingError> {
+        unsafe { self.map_alloc(ptr, size, kind, phys::alloc_zeroed) }
+    }
+
+    unsafe fn map_phys(
+        &mut self,
+        virt: *mut (),
+        phys: PhysPtr<()>,
+        size: usize,
+        kind: MappingKind,
+    ) -> Result<(), MappingError> {
+        let vaddr = virt as usize;
+        check_range(vaddr, size);
+        assert!(phys.addr() % PAGE_SIZE == 0, "phys is misaligned");
+
+        for i in (0..size).step_by(PAGE_SIZE) {
+            let pte = x86_64::find_pte_or_create_with(self.ptroot, vaddr + i, table_flags());
+            pte.map_err(|e| {
+                unsafe { self.unmap(virt, i) }
+                MappingError::AllocError(e)
+            })?
+            .set(PageTableValue::Mapping {
+                phys: phys.cast().byte_add(i),
+                flags: PageTableFlags::from_kind(kind) | PageTableFlags::USER_ACCESSIBLE,
+            })
+            .map_err(|_| {
+                unsafe { self.unmap(virt, i) }
+                MappingError::AlreadyMapped
+            })?
+        }
+
+        Ok(())
+    }
+
+    unsafe fn unmap(&mut self, ptr: *mut (), size: usize) {
+        let vaddr = ptr as usize;
+        check_range(vaddr, size);
+
+        for i in (0..size).step_by(PAGE_SIZE) {
+            if let Some(pte) = x86_64::find_pte(self.ptroot, vaddr + i) {
+                pte.clear();
+                x86_64::flush_tlb(vaddr + i);
+            }
+        }
+
+        self.shootdown(vaddr, size);
+    }
+
+    unsafe fn unmap_free(&mut self, ptr: *mut (), size: usize) {
+        let vaddr = ptr as usize;
+        check_range(vaddr, size);
+
+        let mut frames = Vec::new();
+        for i in (0..size).step_by(PAGE_SIZE) {
+            let Some(pte) = x86_64::find_pte(self.ptroot, vaddr + i) else {
+                continue;
+            };
+            if let Some(PageTableValue::Mapping { phys, flags }) = pte.take() {
+                x86_64::flush_tlb(vaddr + i);
+                if flags.contains(PageTableFlags::OWNED) {
+                    frames.push(phys);
+                }
+            }
+        }
+
+        // other cpus may write to the frames until they have flushed
+        self.shootdown(vaddr, size);
+        for phys in frames {
+            unsafe { phys::dealloc(phys) };
+        }
+    }
+
+    fn query(&mut self, ptr: *const ()) -> Option<MappingKind> {
+        if ptr as usize >= HIGHER_HALF_ADDR {
+            return None;
+        }
+        Some(match x86_64::find_pte(self.ptroot, ptr as usize)?.get()? {
+            PageTableValue::Mapping { flags, .. } => {
+                flags.into_kind().expect("flags should be a known kind")
+            }
+            PageTableValue::Special(SPECIAL_GAURD) => MappingKind::Gaurd,
+            PageTableValue::Special(_) => panic!("invalid special value"),
+        })
+    }
+
+    fn ptroot(&self) -> PhysPtr<PageTable> {
+        self.phys
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(
+            x86_64::current_ptroot().addr() != self.phys.addr(),
+            "dropping the address space in use"
+        );
+        // without pcids, switching tables flushes the lower half from the tlb,
+        // so only cpus that still have these loaded could use stale entries
+        assert!(
+            self.loaded.is_empty(),
+            "dropping an address space loaded on other cpus {:?}",
+            self.loaded
+        );
+
+        // the kernel half is shared, only the lower half belongs to us
+        for pt4_entry in &self.ptroot.entries[..256] {
+            let Some(pt3) = (unsafe { pt4_entry.get_pagetable() }) else {
+                continue;
+            };
+            for pt3_entry in &pt3.entries {
+                let Some(pt2) = (unsafe { pt3_entry.get_pagetable() }) else {
+                    continue;
+                };
+                for pt2_entry in &pt2.entries {
+                    let Some(pt1) = (unsafe { pt2_entry.get_pagetable() }) else {
+                        continue;
+                    };
+                    for pte in &pt1.entries {
+                        if let Some(PageTableValue::Mapping { phys, flags }) = pte.take() {
+                            if flags.contains(PageTableFlags::OWNED) {
+                                unsafe { phys::dealloc(phys) };
+                            }
+                        }
+                    }
+                    free_table(pt2_entry);
+                }
+                free_table(pt3_entry);
+            }
+            free_table(pt4_entry);
+        }
+        unsafe { phys::dealloc(self.phys.cast()) };
+    }
+}
+
+fn free_table(entry: &x86_64::PageTableEntry) {
+    if let Some(PageTableValue::Mapping { phys, .. }) = entry.take() {
+        unsafe { phys::dealloc(phys) };
+    }
+}
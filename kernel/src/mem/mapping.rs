@@ -2,10 +2,10 @@ pub mod x86_64;
 
 use core::{alloc::AllocError, array};
 
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 use x86_64::{find_pte, PageTable, PageTableFlags, PageTableValue};
 
-use crate::{boot::virt_memmap, mem::HIGHER_HALF_ADDR, println};
+use crate::{boot::virt_memmap, mem::HIGHER_HALF_ADDR, println, sync::IrqSpinlock};
 
 use super::{phys, Page, PhysPtr, PAGE_SIZE};
 
@@ -130,25 +130,34 @@ pub trait Mapper {
     /// The address range must be unused
     unsafe fn unmap(&mut self, ptr: *mut (), size: usize);
 
+    /// Unmaps a virtual address range, returning the backing frames to [phys]
+    /// # Safety
+    /// The address range must be unused, and must have been mapped with
+    /// [Mapper::map] or [Mapper::map_zeroed]
+    unsafe fn unmap_free(&mut self, ptr: *mut (), size: usize);
+
     /// Queries the page tables for info about an address
     fn query(&mut self, ptr: *const ()) -> Option<MappingKind>;
 
     fn ptroot(&self) -> PhysPtr<PageTable>;
 }
 
-pub static KERNEL_MAPPER: Lazy<Mutex<KernelMapper>> = Lazy::new(|| Mutex::new(KernelMapper::new()));
+pub static KERNEL_MAPPER: Lazy<IrqSpinlock<KernelMapper>> =
+    Lazy::new(|| IrqSpinlock::new(KernelMapper::new()));
 
-struct DefaultEntries([PageTableValue; 256]);
+/// Kernel half of every top level table, shared so kernel mappings show up in
+/// every address space
+pub(super) struct DefaultEntries(pub(super) [PageTableValue; 256]);
 unsafe impl Sync for DefaultEntries {}
 
-static DEFAULT_ENTRIES: Lazy<DefaultEntries> = Lazy::new(|| {
+pub(super) static DEFAULT_ENTRIES: Lazy<DefaultEntries> = Lazy::new(|| {
     DefaultEntries(array::from_fn(|_| PageTableValue::Mapping {
         phys: phys::alloc_zeroed().expect("critical allocation failed"),
         flags: PageTableFlags::from_kind(MappingKind::Full),
     }))
 });
 
-const SPECIAL_GAURD: u64 = 1;
+pub(super) const SPECIAL_GAURD: u64 = 1;
 
 impl KernelMapper {
     fn new() -> Self {
@@ -173,15 +182,14 @@ impl KernelMapper {
         println!("vmm ready");
         res
     }
-}
 
-impl Mapper for KernelMapper {
-    #[allow(clippy::not_unsafe_ptr_arg_deref)]
-    unsafe fn map(
+    /// Shared by [Mapper::map] and [Mapper::map_zeroed]
+    unsafe fn map_alloc(
         &mut self,
         ptr: *mut (),
         size: usize,
         kind: MappingKind,
+        alloc: fn() -> Result<PhysPtr<Page>, AllocError>,
     ) -> Result<(), MappingError> {
         let vaddr = ptr as usize;
         assert!(vaddr >= HIGHER_HALF_ADDR, "ptr is not in higher half");
@@ -189,65 +197,72 @@ impl Mapper for KernelMapper {
         assert!(size % PAGE_SIZE == 0, "size is misaligned");
 
         for i in (0..size).step_by(PAGE_SIZE) {
-            let pte = x86_64::find_pte_or_create(self.ptroot, vaddr + i);
-            pte.map_err(|e| {
-                unsafe { self.unmap(ptr, i.saturating_sub(PAGE_SIZE)) }
+            // on failure, free the pages mapped so far
+            let pte = x86_64::find_pte_or_create(self.ptroot, vaddr + i).map_err(|e| {
+                unsafe { self.unmap_free(ptr, i) }
                 MappingError::AllocError(e)
-            })?
-            .set(match kind {
+            })?;
+            let value = match kind {
                 MappingKind::Gaurd => PageTableValue::Special(SPECIAL_GAURD),
                 _ => PageTableValue::Mapping {
-                    phys: phys::alloc().map_err(|e| {
-                        unsafe { self.unmap(ptr, i.saturating_sub(PAGE_SIZE)) }
+                    phys: alloc().map_err(|e| {
+                        unsafe { self.unmap_free(ptr, i) }
                         MappingError::AllocError(e)
                     })?,
                     flags: PageTableFlags::from_kind(kind),
                 },
-            })
-            .map_err(|_| {
-                unsafe { self.unmap(ptr, i.saturating_sub(PAGE_SIZE)) }
-                MappingError::AlreadyMapped
-            })?
+            };
+            if pte.set(value).is_err() {
+                if let PageTableValue::Mapping { phys, .. } = value {
+                    unsafe { phys::dealloc(phys) };
+                }
+                unsafe { self.unmap_free(ptr, i) };
+                return Err(MappingError::AlreadyMapped);
+            }
         }
 
         Ok(())
     }
 
-    unsafe fn map_zeroed(
-        &mut self,
-        ptr: *mut (),
-        size: usize,
-        kind: MappingKind,
-    ) -> Result<(), MappingError> {
+    /// Unmaps a single page without freeing it, returns the frame it was
+    /// mapped to
+    ///
+    /// Only the current cpu's tlb is flushed, see [tlb](super::tlb).
+    /// # Safety
+    /// The page must be unused
+    pub unsafe fn unmap_page(&mut self, ptr: *mut ()) -> Option<PhysPtr<Page>> {
         let vaddr = ptr as usize;
-
         assert!(vaddr >= HIGHER_HALF_ADDR, "ptr is not in higher half");
         assert!(vaddr % PAGE_SIZE == 0, "ptr is misaligned");
-        assert!(size % PAGE_SIZE == 0, "size is misaligned");
 
-        for i in (0..size).step_by(PAGE_SIZE) {
-            let pte = x86_64::find_pte_or_create(self.ptroot, vaddr + i);
-            pte.map_err(|e| {
-                unsafe { self.unmap(ptr, i.saturating_sub(PAGE_SIZE)) }
-                MappingError::AllocError(e)
-            })?
-            .set(match kind {
-                MappingKind::Gaurd => PageTableValue::Special(SPECIAL_GAURD),
-                _ => PageTableValue::Mapping {
-                    phys: phys::alloc_zeroed().map_err(|e| {
-                        unsafe { self.unmap(ptr, i.saturating_sub(PAGE_SIZE)) }
-                        MappingError::AllocError(e)
-                    })?,
-                    flags: PageTableFlags::from_kind(kind),
-                },
-            })
-            .map_err(|_| {
-                unsafe { self.unmap(ptr, i.saturating_sub(PAGE_SIZE)) }
-                MappingError::AlreadyMapped
-            })?
+        match x86_64::find_pte(self.ptroot, vaddr)?.take()? {
+            PageTableValue::Mapping { phys, .. } => {
+                x86_64::flush_tlb(vaddr);
+                Some(phys)
+            }
+            PageTableValue::Special(_) => None,
         }
+    }
+}
 
-        Ok(())
+impl Mapper for KernelMapper {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    unsafe fn map(
+        &mut self,
+        ptr: *mut (),
+        size: usize,
+        kind: MappingKind,
+    ) -> Result<(), MappingError> {
+        unsafe { self.map_alloc(ptr, size, kind, phys::alloc) }
+    }
+
+    unsafe fn map_zeroed(
+        &mut self,
+        ptr: *mut (),
+        size: usize,
+        kind: MappingKind,
+    ) -> Result<(), MappingError> {
+        unsafe { self.map_alloc(ptr, size, kind, phys::alloc_zeroed) }
     }
 
     unsafe fn map_phys(
@@ -266,7 +281,7 @@ impl Mapper for KernelMapper {
         for i in (0..size).step_by(PAGE_SIZE) {
             let pte = x86_64::find_pte_or_create(self.ptroot, vaddr + i);
             pte.map_err(|e| {
-                unsafe { self.unmap(virt, i.saturating_sub(PAGE_SIZE)) }
+                unsafe { self.unmap(virt, i) }
                 MappingError::AllocError(e)
             })?
             .set(PageTableValue::Mapping {
@@ -274,7 +289,7 @@ impl Mapper for KernelMapper {
                 flags: PageTableFlags::from_kind(kind),
             })
             .map_err(|_| {
-                unsafe { self.unmap(virt, i.saturating_sub(PAGE_SIZE)) }
+                unsafe { self.unmap(virt, i) }
                 MappingError::AlreadyMapped
             })?
         }
@@ -298,6 +313,26 @@ impl Mapper for KernelMapper {
         // todo: tlb shootdown
     }
 
+    unsafe fn unmap_free(&mut self, ptr: *mut (), size: usize) {
+        let vaddr = ptr as usize;
+
+        assert!(vaddr >= HIGHER_HALF_ADDR, "ptr is not in higher half");
+        assert!(vaddr % PAGE_SIZE == 0, "ptr is misaligned");
+        assert!(size % PAGE_SIZE == 0, "size is misaligned");
+
+        for i in (0..size).step_by(PAGE_SIZE) {
+            let Some(pte) = x86_64::find_pte(self.ptroot, vaddr + i) else {
+                continue;
+            };
+            if let Some(PageTableValue::Mapping { phys, .. }) = pte.take() {
+                x86_64::flush_tlb(vaddr + i);
+                unsafe { phys::dealloc(phys) };
+            }
+        }
+
+        // todo: tlb shootdown
+    }
+
     fn query(&mut self, ptr: *const ()) -> Option<MappingKind> {
         Some(match find_pte(self.ptroot, ptr as usize)?.get()? {
             PageTableValue::Mapping { flags, .. } => {
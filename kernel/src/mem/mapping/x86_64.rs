@@ -1,5 +1,6 @@
 use core::{
     alloc::AllocError,
+    arch::asm,
     fmt::Debug,
     sync::atomic::{AtomicU64, Ordering},
 };
@@ -43,6 +44,8 @@ bitflags! {
         const CACHE_DISABLE = 1 << 4;
         const ACCESSED = 1 << 5;
         const HUGE_PAGE = 1 << 7;
+        /// Available to software, the frame was allocated along with the mapping
+        const OWNED = 1 << 9;
         const EXECUTE_DISABLE = 1 << 63;
     }
 }
@@ -61,13 +64,18 @@ impl PageTableEntry {
     }
 
     pub fn get_or_create(&self) -> Result<PageTableValue, AllocError> {
+        self.get_or_create_with(PageTableFlags::from_kind(MappingKind::Full))
+    }
+
+    /// Like [PageTableEntry::get_or_create], but with the given flags for a new table
+    pub fn get_or_create_with(&self, flags: PageTableFlags) -> Result<PageTableValue, AllocError> {
         match self.get() {
             Some(x) => Ok(x),
             None => {
                 let pagetable = PageTable::new()?;
                 let value = PageTableValue::Mapping {
                     phys: pagetable.cast(),
-                    flags: PageTableFlags::from_kind(MappingKind::Full),
+                    flags,
                 };
                 match self.set(value) {
                     Ok(()) => Ok(value),
@@ -90,6 +98,14 @@ impl PageTableEntry {
     pub unsafe fn get_pagetable_or_create(&self) -> Result<&PageTable, AllocError> {
         Ok(self.get_or_create()?.as_pagetable())
     }
+    /// # Safety
+    /// self must be an entry containing a valid page table
+    pub unsafe fn get_pagetable_or_create_with(
+        &self,
+        flags: PageTableFlags,
+    ) -> Result<&PageTable, AllocError> {
+        Ok(self.get_or_create_with(flags)?.as_pagetable())
+    }
 
     pub fn set(&self, value: PageTableValue) -> Result<(), PageTableValue> {
         self.inner
@@ -101,6 +117,10 @@ impl PageTableEntry {
     pub fn clear(&self) {
         self.inner.store(0, Ordering::Release);
     }
+
+    pub fn take(&self) -> Option<PageTableValue> {
+        PageTableValue::from_u64(self.inner.swap(0, Ordering::AcqRel))
+    }
 }
 
 impl PageTableValue {
@@ -196,8 +216,41 @@ pub fn find_pte(ptroot: &PageTable, virt: usize) -> Option<&PageTableEntry> {
     Some(&pt1.entries[pt1_index])
 }
 
+/// Helper function
+pub fn flush_tlb(virt: usize) {
+    unsafe { asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags)) }
+}
+
+/// Returns the root table the current cpu uses
+pub fn current_ptroot() -> PhysPtr<PageTable> {
+    let cr3: usize;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) }
+    PhysPtr::new(cr3 & 0x000f_ffff_ffff_f000)
+}
+
+/// Flushes every non global entry from the current cpu's tlb
+pub fn flush_tlb_all() {
+    unsafe {
+        asm!(
+            "mov {0}, cr3",
+            "mov cr3, {0}",
+            out(reg) _,
+            options(nostack, preserves_flags)
+        )
+    }
+}
+
 /// Helper function
 pub fn find_pte_or_create(ptroot: &PageTable, virt: usize) -> Result<&PageTableEntry, AllocError> {
+    find_pte_or_create_with(ptroot, virt, PageTableFlags::from_kind(MappingKind::Full))
+}
+
+/// Helper function, creates missing tables with `flags`
+pub fn find_pte_or_create_with(
+    ptroot: &PageTable,
+    virt: usize,
+    flags: PageTableFlags,
+) -> Result<&PageTableEntry, AllocError> {
     let pt4_index = virt.get_bits(39..48);
     let pt3_index = virt.get_bits(30..39);
     let pt2_index = virt.get_bits(21..30);
@@ -205,9 +258,9 @@ pub fn find_pte_or_create(ptroot: &PageTable, virt: usize) -> Result<&PageTableE
 
     let pt4 = ptroot;
 
-    let pt3 = unsafe { pt4.entries[pt4_index].get_pagetable_or_create()? };
-    let pt2 = unsafe { pt3.entries[pt3_index].get_pagetable_or_create()? };
-    let pt1 = unsafe { pt2.entries[pt2_index].get_pagetable_or_create()? };
+    let pt3 = unsafe { pt4.entries[pt4_index].get_pagetable_or_create_with(flags)? };
+    let pt2 = unsafe { pt3.entries[pt3_index].get_pagetable_or_create_with(flags)? };
+    let pt1 = unsafe { pt2.entries[pt2_index].get_pagetable_or_create_with(flags)? };
 
     Ok(&pt1.entries[pt1_index])
 }
@@ -0,0 +1,40 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{Mapper, MappingError, MappingKind, PhysPtr, KERNEL_MAPPER, PAGE_SIZE};
+
+/// Virtual addresses physical ranges outside the hhdm are mapped into
+pub const REGION: Range<usize> = 0xFFFF_B000_0000_0000..0xFFFF_B100_0000_0000;
+
+static NEXT_VADDR: AtomicUsize = AtomicUsize::new(REGION.start);
+
+/// Maps device registers uncached, see [map_physical]
+pub fn map_mmio<T>(phys: PhysPtr<T>, size: usize) -> Result<*mut T, MappingError> {
+    map_physical(phys, size, MappingKind::Mmio)
+}
+
+/// Maps a physical range that may not be covered by the hhdm, such as firmware
+/// tables or device memory
+///
+/// Neither `phys` nor `size` have to be page aligned. The mapping is never freed.
+pub fn map_physical<T>(
+    phys: PhysPtr<T>,
+    size: usize,
+    kind: MappingKind,
+) -> Result<*mut T, MappingError> {
+    let offset = phys.addr() % PAGE_SIZE;
+    let base = PhysPtr::<()>::new(phys.addr() - offset);
+    let size = (offset + size).next_multiple_of(PAGE_SIZE);
+
+    let vaddr = NEXT_VADDR.fetch_add(size, Ordering::Relaxed);
+    assert!(vaddr + size <= REGION.end, "out of mmio address space");
+
+    unsafe {
+        KERNEL_MAPPER
+            .lock()
+            .map_phys(vaddr as *mut (), base, size, kind)
+    }?;
+    Ok((vaddr + offset) as *mut T)
+}
@@ -1,8 +1,12 @@
+mod address_space;
 mod mapping;
+pub mod mmio;
 mod paging;
 pub mod phys;
 mod physptr;
+pub mod tlb;
 
+pub use address_space::*;
 pub use mapping::*;
 pub use paging::*;
 pub use physptr::*;
@@ -1,13 +1,13 @@
 use core::alloc::AllocError;
 use core::ptr::NonNull;
 
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 
-use crate::{boot::phys_memmap_usable, println};
+use crate::{boot::phys_memmap_usable, println, sync::IrqSpinlock};
 
 use super::{Page, PhysPtr, PAGE_SIZE};
 
-static HEAD: Lazy<Mutex<Head>> = Lazy::new(|| {
+static HEAD: Lazy<IrqSpinlock<Head>> = Lazy::new(|| {
     println!("init pmm");
 
     let mut head = Head { next: None };
@@ -20,7 +20,7 @@ static HEAD: Lazy<Mutex<Head>> = Lazy::new(|| {
         }
     }
 
-    let res = Mutex::new(head);
+    let res = IrqSpinlock::new(head);
 
     println!("pmm ready");
     res
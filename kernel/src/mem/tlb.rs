@@ -0,0 +1,60 @@
+//! Tracking when other cpus have flushed unmapped kernel memory from their tlbs
+//!
+//! Unmapping only invalidates the current cpu's tlb, the others may keep using
+//! stale entries until they flush. Waiting for them isn't possible everywhere,
+//! in the allocator they may be spinning on a lock with interrupts disabled.
+//! Instead [shootdown] asks them to flush whenever they can and returns a
+//! [Generation], and [is_flushed] tells once all of them have. Halted cpus
+//! never touch memory again, so they count as flushed.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{arch, boot::cpu_count, cpulocal::CpuLocal};
+
+use super::x86_64;
+
+/// When memory was unmapped, see [shootdown]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Generation(u64);
+
+/// Bumped by every [shootdown]
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// The generation each cpu last flushed in, zero until it has started
+static FLUSHED: CpuLocal<AtomicU64> = CpuLocal::new(|_| AtomicU64::new(0));
+
+/// Ends the current generation after unmapping, and asks the other cpus to
+/// flush without waiting for them
+///
+/// Must only be called once percpu data is ready on this cpu.
+pub fn shootdown() -> Generation {
+    let generation = GENERATION.fetch_add(1, Ordering::AcqRel);
+    flush();
+    arch::request_tlb_flush();
+    Generation(generation)
+}
+
+/// Flushes the current cpu's tlb, covering everything unmapped before
+///
+/// Called on every cpu once it has switched to the kernel's page tables, so
+/// it takes part in [is_flushed].
+pub fn flush() {
+    let current = GENERATION.load(Ordering::Acquire);
+    x86_64::flush_tlb_all();
+    if let Some(cpuid) = arch::try_get_cpuid() {
+        FLUSHED.get(cpuid).fetch_max(current, Ordering::AcqRel);
+    }
+}
+
+/// Marks the current cpu as halted for good
+pub fn park() {
+    if let Some(cpuid) = arch::try_get_cpuid() {
+        FLUSHED.get(cpuid).store(u64::MAX, Ordering::Release);
+    }
+}
+
+/// Returns true once every cpu has flushed memory unmapped before `generation`
+/// ended
+pub fn is_flushed(generation: Generation) -> bool {
+    (0..cpu_count()).all(|cpuid| FLUSHED.get(cpuid).load(Ordering::Acquire) > generation.0)
+}
@@ -1,8 +1,6 @@
 use core::fmt::{self, Write};
 
-use spin::Mutex;
-
-use crate::arch;
+use crate::{arch, sync::IrqSpinlock, time::Instant};
 
 #[macro_export]
 macro_rules! print {
@@ -40,11 +38,59 @@ macro_rules! dbg {
     };
 }
 
-struct Console;
-static CONSOLE: Mutex<Console> = Mutex::new(Console);
+struct Console {
+    /// The next write starts a line, which gets a timestamp
+    line_start: bool,
+}
+static CONSOLE: IrqSpinlock<Console> = IrqSpinlock::new(Console { line_start: true });
 impl Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        arch::debug_print(s);
+        for line in s.split_inclusive('\n') {
+            if self.line_start {
+                let since_boot = Instant::now().since_boot();
+                let mut stamp = Stamp::new();
+                let _ = write!(
+                    stamp,
+                    "[{:>5}.{:06}] ",
+                    since_boot.as_secs(),
+                    since_boot.subsec_micros()
+                );
+                arch::debug_print(stamp.as_str());
+            }
+            arch::debug_print(line);
+            self.line_start = line.ends_with('\n');
+        }
+        Ok(())
+    }
+}
+
+/// Formats a timestamp without going through the console again
+struct Stamp {
+    buf: [u8; 32],
+    len: usize,
+}
+
+impl Stamp {
+    fn new() -> Self {
+        Self {
+            buf: [0; 32],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl Write for Stamp {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
         Ok(())
     }
 }
@@ -53,3 +99,23 @@ impl Write for Console {
 pub fn _print(args: fmt::Arguments) {
     CONSOLE.lock().write_fmt(args).unwrap()
 }
+
+/// Prints without waiting for the console lock, for handlers that can
+/// interrupt its holder
+///
+/// If the console is busy, the output goes straight out and may land in the
+/// middle of the holder's line.
+pub fn print_nonblocking(args: fmt::Arguments) {
+    match CONSOLE.try_lock() {
+        Some(mut console) => console.write_fmt(args).unwrap(),
+        None => Console { line_start: true }.write_fmt(args).unwrap(),
+    }
+}
+
+/// Releases the console lock, in case the cpu holding it was stopped
+///
+/// # Safety
+/// The previous holder must never run again
+pub unsafe fn force_unlock() {
+    unsafe { CONSOLE.force_unlock() };
+}
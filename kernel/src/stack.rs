@@ -1,42 +1,230 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use crate::mem::{Mapper, MappingError, MappingKind, KERNEL_MAPPER, PAGE_SIZE};
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
 
+use crate::{
+    arch,
+    cpulocal::{CpuLocal, Local},
+    mem::{Mapper, MappingError, MappingKind, KERNEL_MAPPER, PAGE_SIZE},
+    sync::IrqSpinlock,
+};
+
+/// A kernel stack with guard pages on either side
+///
+/// Dropping a stack frees it, so it must not be in use anymore. Freed stacks
+/// stay mapped for reuse, since other cpus may still have them in their tlbs.
 pub struct Stack {
     vaddr: usize,
+    size: StackSize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackSize {
+    /// [SMALL_STACK_SIZE], for kernel threads and interrupt stacks
+    Small,
+    /// [STACK_SIZE], for boot stacks
+    Large,
 }
 
 pub const STACK_SIZE: usize = 256 * 1024;
+pub const SMALL_STACK_SIZE: usize = 64 * 1024;
+
+/// Virtual addresses stacks are allocated from
+pub const REGION: Range<usize> = 0xFFFF_A000_0000_0000..0xFFFF_A100_0000_0000;
+
+/// Freed stacks each cpu keeps mapped for reuse, per size
+const POOL_CAPACITY: usize = 4;
+
+static STACK_VADDR: AtomicUsize = AtomicUsize::new(REGION.start);
+
+/// Address ranges that failed to map, per size
+static FREE_VADDRS: [IrqSpinlock<Vec<usize>>; 2] = [const { IrqSpinlock::new(Vec::new()) }; 2];
+
+/// Mapped stacks that didn't fit in the pool of the cpu freeing them, per size
+static FREE_STACKS: [IrqSpinlock<Vec<usize>>; 2] = [const { IrqSpinlock::new(Vec::new()) }; 2];
+
+/// Every stack in use, by the address of its lower guard page
+static STACKS: IrqSpinlock<BTreeMap<usize, StackInfo>> = IrqSpinlock::new(BTreeMap::new());
+
+/// Mapped stacks ready for reuse, per size
+static POOL: CpuLocal<IrqSpinlock<[Vec<usize>; 2]>> =
+    CpuLocal::new(|_| IrqSpinlock::new([Vec::new(), Vec::new()]));
+
+/// Describes a stack in use, for diagnosing overflows
+#[derive(Debug, Clone, Copy)]
+pub struct StackInfo {
+    /// Lowest usable address
+    pub bottom: usize,
+    /// One past the highest usable address, the initial stack pointer
+    pub top: usize,
+    pub name: &'static str,
+    /// Cpu the stack belongs to, if it is tied to one
+    pub cpu: Option<u32>,
+}
+
+impl StackInfo {
+    /// Returns true if `addr` is in one of the guard pages
+    pub fn is_guard(&self, addr: usize) -> bool {
+        (self.bottom - PAGE_SIZE..self.bottom).contains(&addr)
+            || (self.top..self.top + PAGE_SIZE).contains(&addr)
+    }
+}
 
-static STACK_VADDR: AtomicUsize = AtomicUsize::new(0xFFFF_A000_0000_0000);
+impl StackSize {
+    pub const fn bytes(self) -> usize {
+        match self {
+            StackSize::Small => SMALL_STACK_SIZE,
+            StackSize::Large => STACK_SIZE,
+        }
+    }
+
+    /// Size of the address range including guard pages
+    const fn span(self) -> usize {
+        self.bytes() + 2 * PAGE_SIZE
+    }
+
+    const fn index(self) -> usize {
+        match self {
+            StackSize::Small => 0,
+            StackSize::Large => 1,
+        }
+    }
+}
 
 impl Stack {
     pub fn new() -> Result<Self, MappingError> {
-        let vaddr = STACK_VADDR.fetch_add(STACK_SIZE + 2 * PAGE_SIZE, Ordering::Relaxed);
-        let ptr = vaddr as *mut ();
-
-        let mut mapper = KERNEL_MAPPER.lock();
-        unsafe { mapper.map(ptr, PAGE_SIZE, MappingKind::Gaurd) }?;
-        unsafe {
-            mapper.map(
-                ptr.wrapping_byte_add(PAGE_SIZE),
-                STACK_SIZE,
-                MappingKind::ReadWrite,
-            )
-        }?;
-        unsafe {
-            mapper.map(
-                ptr.wrapping_byte_add(PAGE_SIZE + STACK_SIZE),
-                PAGE_SIZE,
-                MappingKind::Gaurd,
-            )
-        }?;
-        drop(mapper);
-
-        Ok(Self { vaddr })
+        Self::with_size(StackSize::Large)
+    }
+
+    pub fn with_size(size: StackSize) -> Result<Self, MappingError> {
+        let stack = Self::alloc(size)?;
+        STACKS
+            .lock()
+            .insert(stack.vaddr, stack.info("unnamed", None));
+        Ok(stack)
+    }
+
+    fn alloc(size: StackSize) -> Result<Self, MappingError> {
+        if let Some(vaddr) = pool().and_then(|pool| pool.lock()[size.index()].pop()) {
+            return Ok(Self { vaddr, size });
+        }
+        if let Some(vaddr) = FREE_STACKS[size.index()].lock().pop() {
+            return Ok(Self { vaddr, size });
+        }
+
+        let vaddr = FREE_VADDRS[size.index()].lock().pop().unwrap_or_else(|| {
+            let vaddr = STACK_VADDR.fetch_add(size.span(), Ordering::Relaxed);
+            assert!(
+                vaddr + size.span() <= REGION.end,
+                "out of stack address space"
+            );
+            vaddr
+        });
+
+        if let Err(err) = unsafe { map_stack(vaddr, size) } {
+            FREE_VADDRS[size.index()].lock().push(vaddr);
+            return Err(err);
+        }
+
+        Ok(Self { vaddr, size })
     }
 
     pub fn stack_pointer(&self) -> usize {
-        self.vaddr + STACK_SIZE + PAGE_SIZE
+        self.vaddr + self.size.bytes() + PAGE_SIZE
+    }
+
+    pub fn size(&self) -> StackSize {
+        self.size
+    }
+
+    /// Records what the stack is used for, shown if it overflows
+    pub fn set_owner(&self, name: &'static str, cpu: Option<u32>) {
+        STACKS.lock().insert(self.vaddr, self.info(name, cpu));
+    }
+
+    fn info(&self, name: &'static str, cpu: Option<u32>) -> StackInfo {
+        StackInfo {
+            bottom: self.vaddr + PAGE_SIZE,
+            top: self.stack_pointer(),
+            name,
+            cpu,
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        STACKS.lock().remove(&self.vaddr);
+
+        if let Some(pool) = pool() {
+            let mut pool = pool.lock();
+            let stacks = &mut pool[self.size.index()];
+            if stacks.len() < POOL_CAPACITY {
+                stacks.push(self.vaddr);
+                return;
+            }
+        }
+
+        FREE_STACKS[self.size.index()].lock().push(self.vaddr);
     }
 }
+
+/// Finds the stack an address belongs to, including its guard pages
+///
+/// Returns `None` if the stack list is locked, so this is safe to call from
+/// exception handlers
+pub fn find(addr: usize) -> Option<StackInfo> {
+    let stacks = STACKS.try_lock()?;
+    let (_, info) = stacks.range(..=addr).next_back()?;
+    (addr < info.top + PAGE_SIZE).then_some(*info)
+}
+
+/// The pool can only be used once percpu data is ready
+fn pool() -> Option<Local<'static, IrqSpinlock<[Vec<usize>; 2]>>> {
+    arch::try_get_cpuid()?;
+    Some(POOL.local())
+}
+
+/// Maps a stack along with the guard pages on either side
+unsafe fn map_stack(vaddr: usize, size: StackSize) -> Result<(), MappingError> {
+    let ptr = vaddr as *mut ();
+    let bytes = size.bytes();
+
+    let mut mapper = KERNEL_MAPPER.lock();
+    unsafe { mapper.map(ptr, PAGE_SIZE, MappingKind::Gaurd) }?;
+    unsafe {
+        mapper.map(
+            ptr.wrapping_byte_add(PAGE_SIZE),
+            bytes,
+            MappingKind::ReadWrite,
+        )
+    }
+    .inspect_err(|_| unsafe { mapper.unmap(ptr, PAGE_SIZE) })?;
+    unsafe {
+        mapper.map(
+            ptr.wrapping_byte_add(PAGE_SIZE + bytes),
+            PAGE_SIZE,
+            MappingKind::Gaurd,
+        )
+    }
+    .inspect_err(|_| unsafe { mapper.unmap_free(ptr, PAGE_SIZE + bytes) })?;
+    drop(mapper);
+
+    #[cfg(feature = "kasan")]
+    {
+        use crate::kasan;
+        kasan::map_shadow(ptr, size.span());
+        kasan::poison(ptr.cast(), PAGE_SIZE, kasan::STACK_GUARD);
+        kasan::unpoison(ptr.wrapping_byte_add(PAGE_SIZE).cast(), bytes);
+        kasan::poison(
+            ptr.wrapping_byte_add(PAGE_SIZE + bytes).cast(),
+            PAGE_SIZE,
+            kasan::STACK_GUARD,
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Kernel symbol table, embedded by the second link pass (see build.rs)
+
+#[used]
+#[link_section = ".ksyms"]
+static TABLE: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+/// Matches build.rs
+const RESTART_INTERVAL: usize = 16;
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    /// Distance of the address from the start of the symbol
+    pub offset: usize,
+}
+
+/// Returns the table through the linker symbols, so code never depends on its
+/// size, and the first link pass lays out code exactly like the second one
+#[cfg(target_os = "none")]
+fn table() -> &'static [u8] {
+    use core::ptr::addr_of;
+
+    extern "C" {
+        static ksyms_start: u8;
+        static ksyms_end: u8;
+    }
+
+    let start = addr_of!(ksyms_start);
+    let end = addr_of!(ksyms_end);
+    unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) }
+}
+
+#[cfg(not(target_os = "none"))]
+fn table() -> &'static [u8] {
+    &[]
+}
+
+fn u16_at(table: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        table.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+fn u32_at(table: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        table.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+fn u64_at(table: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        table.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Returns false if the kernel was built without a symbol table
+pub fn is_available() -> bool {
+    u32_at(table(), 0).is_some_and(|count| count > 0)
+}
+
+/// Finds the function containing `addr`, decoding its name into `buf`
+///
+/// Names longer than `buf` are cut short. Returns `None` if the address is
+/// before the first symbol, or the kernel was built without a symbol table.
+pub fn lookup(addr: usize, buf: &mut [u8]) -> Option<Symbol<'_>> {
+    lookup_in(table(), addr, buf)
+}
+
+fn lookup_in<'a>(table: &[u8], addr: usize, buf: &'a mut [u8]) -> Option<Symbol<'a>> {
+    let count = u32_at(table, 0)? as usize;
+    let base = u64_at(table, 4)? as usize;
+    let names = HEADER_SIZE + count * ENTRY_SIZE;
+
+    let offset_of = |i: usize| u32_at(table, HEADER_SIZE + i * ENTRY_SIZE).unwrap() as usize;
+    let target = addr.checked_sub(base)?;
+    if count == 0 || names > table.len() {
+        return None;
+    }
+
+    // last symbol starting at or before the target
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if offset_of(mid) <= target {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let index = low.checked_sub(1)?;
+
+    let mut len = 0;
+    for i in index - index % RESTART_INTERVAL..=index {
+        let mut pos = names + u32_at(table, HEADER_SIZE + i * ENTRY_SIZE + 4)? as usize;
+        let shared = u16_at(table, pos)? as usize;
+        let suffix_len = u16_at(table, pos + 2)? as usize;
+        pos += 4;
+        let suffix = table.get(pos..pos + suffix_len)?;
+
+        // once a name is cut short every following one is too
+        len = shared.min(len);
+        let copy = suffix.len().min(buf.len() - len);
+        buf[len..len + copy].copy_from_slice(&suffix[..copy]);
+        len += copy;
+    }
+
+    Some(Symbol {
+        name: match core::str::from_utf8(&buf[..len]) {
+            Ok(name) => name,
+            // cut in the middle of a character
+            Err(err) => core::str::from_utf8(&buf[..err.valid_up_to()]).unwrap(),
+        },
+        offset: target - offset_of(index),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{format, string::String, vec::Vec};
+
+    use super::*;
+
+    // the encoder, with the build script's main
+    include!("../build.rs");
+
+    /// Names sharing long prefixes, so some are stored as suffixes and some
+    /// whole at the restarts
+    fn symbols() -> Vec<(u64, String)> {
+        (0..40)
+            .map(|i| {
+                (
+                    0x1000 + i * 0x10,
+                    format!("kernel::module::function_{i:02}"),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let symbols = symbols();
+        let table = encode(symbols.clone());
+        let mut buf = [0; 64];
+
+        assert!(lookup_in(&table, 0xfff, &mut buf).is_none());
+        // around each restart, and the last symbol
+        for i in [0, 1, 15, 16, 17, 31, 32, 39] {
+            let (addr, name) = &symbols[i];
+            let symbol = lookup_in(&table, *addr as usize + 3, &mut buf).unwrap();
+            assert_eq!(symbol.name, name);
+            assert_eq!(symbol.offset, 3);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let symbols = symbols();
+        let table = encode(symbols.clone());
+
+        // cut within the part shared with the previous name
+        let mut buf = [0; 10];
+        let symbol = lookup_in(&table, symbols[17].0 as usize, &mut buf).unwrap();
+        assert_eq!(symbol.name, "kernel::mo");
+
+        // cut within the suffix, of a name stored whole at a restart
+        let mut buf = [0; 20];
+        let symbol = lookup_in(&table, symbols[16].0 as usize, &mut buf).unwrap();
+        assert_eq!(symbol.name, "kernel::module::func");
+    }
+
+    #[test]
+    fn truncated_in_character() {
+        let table = encode(Vec::from([(0x1000, String::from("añb"))]));
+        let mut buf = [0; 2];
+        assert_eq!(lookup_in(&table, 0x1000, &mut buf).unwrap().name, "a");
+    }
+}
@@ -0,0 +1,124 @@
+//! Locks that can be shared with interrupt handlers
+//!
+//! A handler that spins on a lock the code it interrupted holds never gets it
+//! back, so anything an interrupt handler may lock, directly or by printing or
+//! allocating, has to keep interrupts disabled while it is held.
+
+use core::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use crate::arch;
+
+/// Disables interrupts until dropped, then enables them again if they were
+/// enabled before
+pub struct InterruptGuard {
+    were_enabled: bool,
+    // interrupt state belongs to a cpu, the guard can't move to another one
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl InterruptGuard {
+    pub fn new() -> Self {
+        let were_enabled = arch::interrupts_enabled();
+        arch::disable_interrupts();
+        Self {
+            were_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn were_enabled(&self) -> bool {
+        self.were_enabled
+    }
+}
+
+impl Default for InterruptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.were_enabled {
+            arch::enable_interrupts();
+        }
+    }
+}
+
+/// A spinlock that disables interrupts on the cpu holding it
+///
+/// Interrupts stay disabled while waiting for the lock, as well as while
+/// holding it. Each guard restores the interrupt state from before it locked,
+/// so locks can be released in any order.
+pub struct IrqSpinlock<T> {
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqSpinlockGuard<'a, T> {
+    // dropped first, so the lock is released before interrupts come back on
+    guard: spin::MutexGuard<'a, T>,
+    _interrupts: InterruptGuard,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let interrupts = InterruptGuard::new();
+        IrqSpinlockGuard {
+            guard: self.inner.lock(),
+            _interrupts: interrupts,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let interrupts = InterruptGuard::new();
+        Some(IrqSpinlockGuard {
+            guard: self.inner.try_lock()?,
+            _interrupts: interrupts,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Marks the lock free without touching this cpu's interrupt state
+    ///
+    /// # Safety
+    /// Whoever held the lock must never run again
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for IrqSpinlock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
@@ -0,0 +1,295 @@
+//! Monotonic time since boot, and the wall clock
+
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::Duration,
+};
+
+use spin::Once;
+
+use crate::{arch, println};
+
+/// Wall clock time at [Instant::BOOT], in nanoseconds since the unix epoch
+static BOOT_WALL_CLOCK: Once<u64> = Once::new();
+
+/// Returns nanoseconds since boot, the same on every cpu
+///
+/// Zero until the clock is calibrated early in boot.
+pub fn monotonic() -> u64 {
+    arch::nanos_since_boot()
+}
+
+/// A point in time, measured with [monotonic]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    /// The time the clock was calibrated
+    pub const BOOT: Instant = Instant { nanos: 0 };
+
+    pub fn now() -> Self {
+        Self { nanos: monotonic() }
+    }
+
+    /// Returns the time since `earlier`, or zero if it is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_add(nanos)?,
+        })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_sub(nanos)?,
+        })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs)
+            .expect("overflow adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs)
+            .expect("overflow subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Seconds since boot with microsecond precision, like `12.345678`
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.nanos / 1_000_000_000;
+        let micros = self.nanos % 1_000_000_000 / 1000;
+        write!(f, "{secs}.{micros:06}")
+    }
+}
+
+/// Reads the hardware clock once to anchor [now], called once after the
+/// monotonic clock is calibrated
+pub fn init() {
+    crate::assert_once!();
+
+    BOOT_WALL_CLOCK.call_once(|| {
+        let Some(date) = arch::read_wall_clock() else {
+            println!("time: no wall clock, counting from the epoch");
+            return 0;
+        };
+        println!("time: {date} UTC");
+
+        let since_epoch = u64::try_from(date.to_unix())
+            .unwrap_or(0)
+            .saturating_mul(1_000_000_000);
+        since_epoch.saturating_sub(monotonic())
+    });
+}
+
+/// Returns the current wall clock time
+///
+/// Follows the monotonic clock from the hardware clock reading in [init], so
+/// it is only accurate to about a second and never jumps.
+pub fn now() -> SystemTime {
+    let boot = BOOT_WALL_CLOCK.get().copied().unwrap_or(0);
+    SystemTime {
+        nanos: boot + monotonic(),
+    }
+}
+
+/// A point in wall clock time, UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime {
+    /// Nanoseconds since the unix epoch
+    nanos: u64,
+}
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime { nanos: 0 };
+
+    pub fn since_epoch(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    /// Returns the time since `earlier`, or zero if it is later than `self`
+    pub fn duration_since(&self, earlier: SystemTime) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn date(&self) -> DateTime {
+        DateTime::from_unix((self.nanos / 1_000_000_000) as i64)
+    }
+}
+
+/// Like `2024-10-04T13:05:09.123456Z`
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let date = self.date();
+        let micros = self.nanos % 1_000_000_000 / 1000;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{micros:06}Z",
+            date.year, date.month, date.day, date.hour, date.minute, date.second
+        )
+    }
+}
+
+/// A calendar date and time of day in the proleptic gregorian calendar
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: i64,
+    /// 1..=12
+    pub month: u8,
+    /// 1..=31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns seconds since the unix epoch, negative before it
+    pub fn to_unix(&self) -> i64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    pub fn from_unix(secs: i64) -> Self {
+        let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+        let secs = secs.rem_euclid(86_400);
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+/// Like `2024-10-04 13:05:09`
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// both from http://howardhinnant.github.io/date_algorithms.html, with years
+// starting in march so the leap day is the last one
+
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u8;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(year: i64, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn known_dates() {
+        let dates = [
+            (0, date(1970, 1, 1, 0, 0, 0)),
+            (-1, date(1969, 12, 31, 23, 59, 59)),
+            (951_782_400, date(2000, 2, 29, 0, 0, 0)),
+            (1_728_047_109, date(2024, 10, 4, 13, 5, 9)),
+            // not leap years, divisible by 100 but not 400
+            (-2_203_891_200, date(1900, 3, 1, 0, 0, 0)),
+            (4_107_542_399, date(2100, 2, 28, 23, 59, 59)),
+        ];
+        for (secs, date) in dates {
+            assert_eq!(date.to_unix(), secs, "{date}");
+            assert_eq!(DateTime::from_unix(secs), date, "{secs}");
+        }
+    }
+
+    #[test]
+    fn round_trip_days() {
+        // every day across a few 400 year eras, around the epoch
+        for days in -200_000..200_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+}